@@ -1,11 +1,22 @@
 mod my_app;
 
 use anyhow::Result;
-use my_app::MyApp;
+use my_app::{Command, MyApp};
 
 fn main() -> Result<()> {
-    let app = MyApp::new()?;
-    let count = app.run()?;
+    match Command::from_args()? {
+        Command::Copy(options) if options.watch => MyApp::new(options)?.watch(),
+        Command::Copy(options) => {
+            let app = MyApp::new(options)?;
+            let count = app.run()?;
 
-    Ok(println!("\n{}", count))
+            println!("\n{}", count);
+            app.check_failures()
+        }
+        Command::List(options) => my_app::list(&options),
+        Command::Stats(options) => my_app::stats(&options),
+        Command::Check(options) => my_app::check(&options),
+        Command::Verify(options) => my_app::verify(&options),
+        Command::Sync(options) => my_app::sync(&options),
+    }
 }
@@ -1,29 +1,76 @@
 mod app_options;
+mod archive;
+mod backup;
+mod checksum;
+mod explain;
+mod extract;
 mod ignore_file;
+mod list;
 mod logger;
+mod manifest;
+mod metadata;
+mod report;
+mod stats;
+mod symlink;
+mod sync;
+mod temp_file;
 mod type_counter;
+mod verify;
+mod watch;
 mod working_indicator;
 
-use anyhow::{Context, Result};
-use app_options::AppOptions;
+use anyhow::{anyhow, bail, Context, Result};
+pub use app_options::Command;
+use app_options::{AppOptions, CompareMode, ConflictPolicy, SymlinkPolicy};
+use archive::ArchiveWriter;
+use backup::Backup;
+use checksum::Checksum;
+pub use explain::check;
 use fs_extra::dir::get_size;
-use ignore_file::IgnoreFile;
+use ignore_file::{DirRules, IgnoreFile, Rule, Verdict};
+pub use list::list;
 use logger::Logger;
+use manifest::{Entry, Manifest, MANIFEST_FILE_NAME};
 use rayon::prelude::*;
-use std::cell::RefCell;
+use report::{Action, Report};
+pub use stats::stats;
 use std::collections::HashMap;
 use std::fs;
 use std::io::{stdout, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+pub use sync::sync;
 use type_counter::TypeCounter;
+pub use verify::verify;
+use verify::Outcome;
 use working_indicator::WorkingIndicator;
 
+type DirInfo = HashMap<PathBuf, (u64, u64)>;
+
+/// Result of comparing a source file with its existing copy in the destination
+enum Comparison {
+    Changed,
+    Older,
+    Identical,
+    /// The destination is newer than the source although times are preserved, so it was
+    /// probably changed in the destination
+    Newer,
+}
+
 pub struct MyApp {
     options: AppOptions,
     ignore_file: IgnoreFile,
     log: Logger,
-    db: HashMap<PathBuf, (u64, u64)>,
-    progress: RefCell<WorkingIndicator>,
+    db: DirInfo,
+    progress: Mutex<WorkingIndicator>,
+    report: Option<Report>,
+    manifest: Option<Manifest>,
+    /// Set if copying into an archive rather than a directory
+    archive: Option<ArchiveWriter>,
+    /// Files written by this run, to be checked with `--verify`
+    written: Mutex<Vec<PathBuf>>,
+    failures: Mutex<Vec<(PathBuf, anyhow::Error)>>,
 }
 
 fn is_symlink<P: AsRef<Path>>(path: P) -> bool {
@@ -33,24 +80,73 @@ fn is_symlink<P: AsRef<Path>>(path: P) -> bool {
         .unwrap_or(false)
 }
 
-impl MyApp {
-    pub fn new() -> Result<Self> {
-        let options = AppOptions::from_args()?;
+/// Action counted for a file written to the destination. Resolved conflicts are counted as
+/// such, even though the file is written.
+fn written_action(conflict: bool) -> Action {
+    if conflict {
+        Action::Conflict
+    } else {
+        Action::Copied
+    }
+}
+
+/// Let a read-only file be replaced or deleted.
+fn make_writable(path: &Path, meta: &fs::Metadata) -> Result<()> {
+    let mut permission = meta.permissions();
+    if permission.readonly() {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            // The mode is shared with the other links of the file, e.g. in a --link-dest
+            // snapshot, and is not needed to replace or delete it here anyway
+            if meta.nlink() > 1 {
+                return Ok(());
+            }
+            use std::os::unix::fs::PermissionsExt;
+            permission.set_mode(permission.mode() | 0o200);
+        }
+        #[cfg(not(unix))]
+        permission.set_readonly(false);
+        fs::set_permissions(path, permission)?;
+    }
+    Ok(())
+}
 
-        let ignore_file = IgnoreFile::new(options.src.as_path(), options.ignore_file.as_path())
-            .context("Ignore file syntax error.")?;
+impl MyApp {
+    pub fn new(options: AppOptions) -> Result<Self> {
+        let ignore_file = IgnoreFile::load(&options.src, &options.rules)?;
 
         let log = match &options.log_file {
             Some(path) => Logger::to_file(path),
-            _ => Logger::new(true),
+            // In dry-run mode the log is the report, so print it to the console
+            _ => Logger::new(!options.dry_run),
         };
 
         print!("Collecting info of the source directory...");
         let _ = stdout().flush();
-        let (db, count, _) = Self::collect_dir_info(&options.src)?;
+        let follow = options.symlinks == SymlinkPolicy::Follow;
+        let (db, count, _) = Self::collect_dir_info(&options.src, follow, &[])?;
         println!("Done.");
 
-        let progress = RefCell::new(WorkingIndicator::new(count));
+        // The entries of an archive are only known while extracting them
+        let progress = Mutex::new(WorkingIndicator::new(
+            count,
+            !options.dry_run && options.src_archive.is_none(),
+        ));
+        let report = options
+            .report
+            .as_ref()
+            .map(|(format, path)| Report::new(*format, path));
+        let manifest = if options.manifest {
+            Some(Manifest::load(&options.src, &options.dest)?)
+        } else {
+            None
+        };
+
+        let archive = match options.archive {
+            Some(format) if !options.dry_run => Some(ArchiveWriter::create(&options.dest, format)?),
+            _ => None,
+        };
 
         Ok(MyApp {
             options,
@@ -58,25 +154,175 @@ impl MyApp {
             log,
             db,
             progress,
+            report,
+            manifest,
+            archive,
+            written: Mutex::new(Vec::new()),
+            failures: Mutex::new(Vec::new()),
         })
     }
 
     pub fn run(&self) -> Result<TypeCounter> {
-        self.progress.borrow_mut().init();
-        let result = self.traverse_tree(&self.options.src);
-        self.progress.borrow().done();
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.options.jobs)
+            .build()?;
+
+        self.progress.lock().unwrap().init();
+        let result = match self.options.src_archive {
+            Some(format) => self.extract_archive(format),
+            _ => pool.install(|| self.traverse_tree(&self.options.src, &[], &DirRules::empty())),
+        };
+        self.progress.lock().unwrap().done();
+        if self.options.archive.is_some() {
+            return self.write_report(self.finish_archive(result));
+        }
+        let result = result.map(|counter| counter + &self.verify_written());
+
+        if self.options.mirror && self.options.dest.exists() {
+            let root_rules = DirRules::empty();
+            let rules = self.ignore_file.dir_rules(&self.options.src, &root_rules)?;
+            // Files of slimcopy itself are not part of the copy
+            let own_files = [self.options.dest.join(MANIFEST_FILE_NAME)];
+            let removed = self
+                .options
+                .dest
+                .read_dir()?
+                .map(|entry| entry.map(|entry| entry.path()))
+                .filter(|path| !matches!(path, Ok(path) if own_files.contains(path)))
+                .map(|path| self.prune_tree(path?.as_path(), false, &rules))
+                .collect::<Result<Vec<TypeCounter>>>()?;
+            let result =
+                result.map(|counter| removed.iter().fold(counter, |accu, item| accu + item));
+            return self.write_report(self.save_manifest(result));
+        }
+        self.write_report(self.save_manifest(result))
+    }
+
+    /// Fail if any file could not be processed in `--keep-going` mode, listing all of them.
+    pub fn check_failures(&self) -> Result<()> {
+        let failures = self.failures.lock().unwrap();
+        if failures.is_empty() {
+            return Ok(());
+        }
+        let list = failures
+            .iter()
+            .map(|(path, e)| format!("  {}: {:#}", path.display(), e))
+            .collect::<Vec<_>>()
+            .join("\n");
+        Err(anyhow!("{} file(s) failed:\n{}", failures.len(), list))
+    }
+
+    /// Handle the failure of processing a path. With `--keep-going`, it is counted and the run
+    /// continues; otherwise the error is passed on and only the first failure is reported.
+    fn recover(&self, path: &Path, result: Result<TypeCounter>) -> Result<TypeCounter> {
+        let e = match result {
+            Err(e) => e,
+            ok => return ok,
+        };
+        let mut failures = self.failures.lock().unwrap();
+        if self.options.keep_going {
+            self.log.add(&format!("Fail {}: {:#}", path.display(), e));
+            self.record(path, Action::Error, 0, None);
+            failures.push((path.to_path_buf(), e));
+            Ok(TypeCounter::new().count_failed())
+        } else {
+            if failures.is_empty() {
+                self.record(path, Action::Error, 0, None);
+                failures.push((path.to_path_buf(), anyhow!("{:#}", e)));
+            }
+            Err(e)
+        }
+    }
+
+    /// Complete the archive, or drop it if the run failed.
+    fn finish_archive(&self, result: Result<TypeCounter>) -> Result<TypeCounter> {
+        if let Some(archive) = &self.archive {
+            if result.is_ok() {
+                archive.finish()?;
+            } else {
+                archive.discard();
+            }
+        }
         result
     }
 
-    fn collect_dir_info(item: &Path) -> Result<(HashMap<PathBuf, (u64, u64)>, u64, u64)> {
-        if item.symlink_metadata()?.file_type().is_symlink() {
+    /// Check that the files written by this run match their source, counting the mismatches
+    /// as failures.
+    fn verify_written(&self) -> TypeCounter {
+        let written = std::mem::take(&mut *self.written.lock().unwrap());
+        written
+            .par_iter()
+            .map(|src_path| {
+                let error = match self
+                    .dest_path_of(src_path)
+                    .and_then(|dest_path| verify::compare_file(src_path, &dest_path))
+                {
+                    Ok(Outcome::Match) => return TypeCounter::new(),
+                    Ok(Outcome::Differs) => anyhow!("Copy does not match the source"),
+                    Ok(Outcome::Missing) => anyhow!("Copy is missing"),
+                    Err(e) => e.context("Cannot verify copy"),
+                };
+                self.log
+                    .add(&format!("Mismatch {}: {:#}", src_path.display(), error));
+                self.record(src_path, Action::Mismatch, 0, None);
+                self.failures
+                    .lock()
+                    .unwrap()
+                    .push((src_path.to_path_buf(), error));
+                TypeCounter::new().count_mismatched()
+            })
+            .collect::<Vec<_>>()
+            .iter()
+            .sum()
+    }
+
+    fn write_report(&self, result: Result<TypeCounter>) -> Result<TypeCounter> {
+        if let Some(report) = &self.report {
+            // Write what was done so far even if the run failed
+            report.write(result.as_ref().ok())?;
+        }
+        result
+    }
+
+    fn save_manifest(&self, result: Result<TypeCounter>) -> Result<TypeCounter> {
+        if let Some(manifest) = &self.manifest {
+            // Entries are only recorded for files processed successfully, so save them anyway
+            if !self.options.dry_run {
+                manifest.save()?;
+            }
+        }
+        result
+    }
+
+    /// Add a source path to the report, relative to the source directory.
+    fn record(&self, path: &Path, action: Action, size: u64, rule: Option<&Rule>) {
+        if let Some(report) = &self.report {
+            let path = path.strip_prefix(&self.options.src).unwrap_or(path);
+            report.add(path, action, size, rule.map(|rule| rule.to_string()));
+        }
+    }
+
+    fn collect_dir_info(
+        item: &Path,
+        follow: bool,
+        visited: &[PathBuf],
+    ) -> Result<(DirInfo, u64, u64)> {
+        if !follow && item.symlink_metadata()?.file_type().is_symlink() {
             Ok((HashMap::new(), 0, 0))
         } else if item.is_dir() {
+            let mut visited = visited.to_vec();
+            if follow {
+                let real_path = item.canonicalize()?;
+                if visited.contains(&real_path) {
+                    return Ok((HashMap::new(), 0, 0));
+                }
+                visited.push(real_path);
+            }
             item.read_dir()?
                 .par_bridge()
                 .map(|entry| {
                     let entry = entry.context("I/O error")?;
-                    Self::collect_dir_info(entry.path().as_path())
+                    Self::collect_dir_info(entry.path().as_path(), follow, &visited)
                 })
                 .collect::<Result<Vec<_>>>()
                 .map(|v| {
@@ -98,77 +344,1088 @@ impl MyApp {
         }
     }
 
-    fn traverse_tree(&self, path: &Path) -> Result<TypeCounter> {
-        if self.ignore_file.is_ignored(path, path.is_dir()) {
-            let (file_count, size) = if is_symlink(path) {
-                (0, 0)
-            } else if path.is_dir() {
+    fn traverse_tree(
+        &self,
+        path: &Path,
+        visited: &[PathBuf],
+        rules: &DirRules,
+    ) -> Result<TypeCounter> {
+        let result = self.process_path(path, visited, rules);
+        self.recover(path, result)
+    }
+
+    /// `visited` holds the canonical paths of the directories being traversed when symbolic
+    /// links are followed, so that links pointing back to one of them are not entered again.
+    /// `rules` holds the rules read from the rule files of the directories containing `path`.
+    fn process_path(
+        &self,
+        path: &Path,
+        visited: &[PathBuf],
+        rules: &DirRules,
+    ) -> Result<TypeCounter> {
+        if self.options.archive.is_some() && archive::is_archive_file(&self.options.dest, path) {
+            return Ok(TypeCounter::new());
+        }
+        let follow = self.options.symlinks == SymlinkPolicy::Follow;
+        let is_link = is_symlink(path);
+        let is_dir = (follow || !is_link) && path.is_dir();
+
+        let verdict = self.ignore_file.verdict(rules, path, is_dir);
+        let rule = match verdict {
+            Verdict::Ignored(rule) => Some(rule),
+            Verdict::NotIncluded => None,
+            Verdict::Copied(rule) => rule,
+        };
+        if !matches!(verdict, Verdict::Copied(_)) {
+            let (file_count, size) = if is_dir {
                 *self.db.get(&path.to_path_buf()).unwrap_or(&(0, 0))
+            } else if is_link {
+                (0, 0)
             } else {
                 (1, get_size(path).unwrap_or(0))
             };
-            self.log
-                .add(&format!("Skip {}, {} files", path.display(), file_count));
-            self.progress.borrow_mut().update(file_count);
+            self.log_skip(path, file_count, rule);
+            self.progress.lock().unwrap().update(file_count);
+            self.record(path, Action::Skipped, size, rule);
             let counter = TypeCounter::new();
             Ok(counter.count_skipped(file_count, size))
-        } else if path.is_dir() {
-            path.read_dir()?
-                .map(|entry| self.traverse_tree(entry?.path().as_path()))
+        } else if is_dir {
+            let mut visited = visited.to_vec();
+            if follow {
+                let real_path = path.canonicalize()?;
+                if visited.contains(&real_path) {
+                    self.log
+                        .add(&format!("Skip symbolic link loop \"{}\"", path.display()));
+                    self.record(path, Action::Symlink, 0, rule);
+                    return Ok(TypeCounter::new().count_symlink());
+                }
+                visited.push(real_path);
+            }
+            let rules = self.ignore_file.dir_rules(path, rules)?;
+            if let Some(archive) = &self.archive {
+                if path != self.options.src {
+                    archive.add_dir(path.strip_prefix(&self.options.src)?, path)?;
+                }
+            }
+            let counter = path
+                .read_dir()?
+                .collect::<std::io::Result<Vec<_>>>()?
+                .par_iter()
+                .map(|entry| self.traverse_tree(entry.path().as_path(), &visited, &rules))
                 .collect::<Result<Vec<TypeCounter>>>()
-                .map(|v| v.iter().sum())
+                .map(|v| v.iter().sum())?;
+
+            let dest_path = self.dest_path_of(path)?;
+            if !self.options.dry_run && dest_path.is_dir() {
+                let removed = temp_file::remove_stale(&dest_path).with_context(|| {
+                    format!(
+                        "Cannot delete temporary files in \"{}\"",
+                        dest_path.display()
+                    )
+                })?;
+                for removed in removed {
+                    self.log.add(&format!("Delete {}", removed.display()));
+                }
+            }
+            // Directory timestamps change while its entries are written, so apply them afterwards
+            if self.options.preserve.any() && !self.options.dry_run && dest_path.is_dir() {
+                metadata::apply(path, &dest_path, &self.options.preserve).with_context(|| {
+                    format!("Cannot preserve attributes of \"{}\"", dest_path.display())
+                })?;
+            }
+            Ok(counter)
         } else {
-            self.progress.borrow_mut().update(1);
-            self.copy_file(path)
+            self.progress.lock().unwrap().update(1);
+            let result = if is_link && !(follow && path.exists()) {
+                self.copy_symlink(path)
+            } else if self.options.archive.is_some() {
+                self.add_to_archive(path)
+            } else {
+                self.copy_file(path)
+            };
+            let (action, size) = result?;
+            let written = match action {
+                Action::Copied | Action::Linked => true,
+                Action::Conflict => self.options.conflict.replaces(),
+                _ => false,
+            };
+            if self.options.verify && !self.options.dry_run && written {
+                self.written.lock().unwrap().push(path.to_path_buf());
+            }
+            self.record(path, action, size, rule);
+            Ok(TypeCounter::new().count(action, size))
         }
     }
 
-    fn copy_file(&self, src_path: &Path) -> Result<TypeCounter> {
-        let counter = TypeCounter::new();
-        if is_symlink(src_path) {
-            self.log
-                .add(&format!("Skip symbolic link \"{}\"", src_path.display()));
-            Ok(counter.count_symlink())
-            // TODO: There should be better ways to handle symbolic links...
-            // let link_target = src_path.read_link()?;
-            // std::os::windows::fs::symlink_dir(link_target, dest_path)?;
+    /// Log a path left out by the rules, with the rule deciding it when explaining.
+    fn log_skip(&self, path: &Path, file_count: u64, rule: Option<&Rule>) {
+        match rule {
+            _ if !self.options.explain => {
+                self.log
+                    .add(&format!("Skip {}, {} files", path.display(), file_count))
+            }
+            Some(rule) => self.log.add(&format!(
+                "Skip {}, {} files, by {}",
+                path.display(),
+                file_count,
+                rule
+            )),
+            None => self.log.add(&format!(
+                "Skip {}, {} files, not included",
+                path.display(),
+                file_count
+            )),
+        }
+    }
+
+    fn dest_path_of(&self, src_path: &Path) -> Result<PathBuf> {
+        Ok(self
+            .options
+            .dest
+            .join(src_path.strip_prefix(&self.options.src)?))
+    }
+
+    fn create_parent_dir(&self, dest_path: &Path) -> Result<()> {
+        let dest_dir = dest_path.parent().unwrap();
+        if !dest_dir.exists() && !self.options.dry_run {
+            fs::create_dir_all(dest_dir)
+                .with_context(|| format!("Cannot create directory \"{}\"", dest_dir.display()))?;
+        }
+        Ok(())
+    }
+
+    fn add_to_archive(&self, src_path: &Path) -> Result<(Action, u64)> {
+        self.log.add(&format!("Add {}", src_path.display()));
+        match &self.archive {
+            Some(archive) => {
+                let size = archive
+                    .add_file(src_path.strip_prefix(&self.options.src)?, src_path)
+                    .with_context(|| {
+                        format!("Failed to add \"{}\" to the archive", src_path.display())
+                    })?;
+                Ok((Action::Copied, size))
+            }
+            // Dry run
+            _ => Ok((Action::Copied, get_size(src_path).unwrap_or(0))),
+        }
+    }
+
+    fn copy_file(&self, src_path: &Path) -> Result<(Action, u64)> {
+        let dest_path = self.dest_path_of(src_path)?;
+        // Follow links here, so that followed symbolic links compare their targets
+        let src_meta = src_path.metadata()?;
+        // The source is hashed once, for both the comparison and the manifest
+        let src_hash = match self.options.compare {
+            CompareMode::Hash
+                if self.manifest.is_some()
+                    || self.options.link_dest.is_some()
+                    || dest_path.exists() =>
+            {
+                Some(checksum::file_hash(src_path)?)
+            }
+            _ => None,
+        };
+
+        // If force-copy is not set, copy only changed files
+        let known = if self.options.force_copy {
+            None
         } else {
-            let dest_path = self
-                .options
-                .dest
-                .join(src_path.strip_prefix(&self.options.src)?);
-            let dest_dir = dest_path.parent().unwrap();
-            if dest_path.exists() {
-                let src_meta = src_path.symlink_metadata()?;
-                let dest_meta = dest_path.symlink_metadata()?;
-
-                // If force-copy is not set, copy only newer files
-                if !self.options.force_copy {
-                    match (src_meta.modified(), dest_meta.modified()) {
-                        (Ok(src_time), Ok(dest_time)) if src_time > dest_time => (),
-                        _ => {
-                            self.log.add(&format!("Old {}", src_path.display()));
-                            return Ok(counter.count_no_update(get_size(src_path).unwrap_or(0)));
+            self.compare_with_manifest(src_path, &src_meta, src_hash, &dest_path)?
+        };
+        if let Some(comparison @ (Comparison::Older | Comparison::Identical)) = known {
+            return Ok(self.unchanged(src_path, src_meta.len(), comparison));
+        }
+
+        let mut conflict = false;
+        if dest_path.exists() {
+            let dest_meta = dest_path.symlink_metadata()?;
+
+            if !self.options.force_copy && known.is_none() {
+                let mut comparison =
+                    self.compare(src_path, &src_meta, src_hash, &dest_path, &dest_meta)?;
+                // The source changed since the last run, and so did its copy
+                if let Comparison::Changed | Comparison::Older = comparison {
+                    if self.changed_since_copy(src_path, &dest_meta)? {
+                        comparison = Comparison::Newer;
+                    }
+                }
+                match comparison {
+                    Comparison::Changed => (),
+                    Comparison::Newer => {
+                        if let Some(kept) =
+                            self.resolve_conflict(src_path, &dest_path, src_meta.len())?
+                        {
+                            return Ok(kept);
                         }
-                    };
+                        conflict = true;
+                    }
+                    comparison => {
+                        self.remember(src_path, &src_meta, src_hash, &dest_meta)?;
+                        return Ok(self.unchanged(src_path, src_meta.len(), comparison));
+                    }
                 }
+            }
 
-                // Remove read-only attribute before overwriting existing file
-                let mut permission = dest_meta.permissions();
-                if permission.readonly() {
-                    permission.set_readonly(false);
-                    fs::set_permissions(&dest_path, permission)?;
+            self.prepare_overwrite(&dest_path, &dest_meta, conflict)?;
+        } else {
+            self.create_parent_dir(&dest_path)?;
+            if !self.options.force_copy {
+                if let Some(size) =
+                    self.link_unchanged(src_path, &src_meta, src_hash, &dest_path)?
+                {
+                    return Ok((Action::Linked, size));
+                }
+            }
+        }
+
+        let action = written_action(conflict);
+        self.log.add(&format!("Copy {}", src_path.display()));
+        if self.options.dry_run {
+            Ok((action, get_size(src_path).unwrap_or(0)))
+        } else {
+            let size = temp_file::replace_with(&dest_path, |temp_path| {
+                self.write_file(src_path, temp_path, &dest_path)
+            })?;
+            self.remember(src_path, &src_meta, src_hash, &dest_path.metadata()?)?;
+            Ok((action, size))
+        }
+    }
+
+    /// Get an existing destination file ready to be replaced, unless `resolve_conflict` already
+    /// moved it away: back it up, or make it writable.
+    fn prepare_overwrite(
+        &self,
+        dest_path: &Path,
+        dest_meta: &fs::Metadata,
+        conflict: bool,
+    ) -> Result<()> {
+        if conflict && self.options.conflict == ConflictPolicy::Rename {
+            // Nothing left to back up or overwrite
+        } else if let Some(backup) = &self.options.backup {
+            self.back_up(backup, dest_path)?;
+        } else if !self.options.dry_run {
+            // Remove read-only attribute before overwriting existing file
+            make_writable(dest_path, dest_meta)?;
+        }
+        Ok(())
+    }
+
+    /// With `--link-dest`, hard link the copy of the file in the previous snapshot if the file
+    /// is unchanged since, as decided by the usual comparison. Returns the size of the file if
+    /// it was linked.
+    fn link_unchanged(
+        &self,
+        src_path: &Path,
+        src_meta: &fs::Metadata,
+        src_hash: Option<Checksum>,
+        dest_path: &Path,
+    ) -> Result<Option<u64>> {
+        let link_dest = match &self.options.link_dest {
+            Some(link_dest) => link_dest,
+            _ => return Ok(None),
+        };
+        let previous = link_dest.join(src_path.strip_prefix(&self.options.src)?);
+        let previous_meta = match previous.symlink_metadata() {
+            Ok(meta) if meta.is_file() => meta,
+            _ => return Ok(None),
+        };
+        // A snapshot changed after it was taken is not linked either
+        if let Comparison::Changed | Comparison::Newer =
+            self.compare(src_path, src_meta, src_hash, &previous, &previous_meta)?
+        {
+            return Ok(None);
+        }
+
+        self.log.add(&format!(
+            "Hard link {} from {}",
+            src_path.display(),
+            previous.display()
+        ));
+        if !self.options.dry_run {
+            fs::hard_link(&previous, dest_path).with_context(|| {
+                format!(
+                    "Failed to hard link \"{}\" to \"{}\"",
+                    dest_path.display(),
+                    previous.display()
+                )
+            })?;
+            self.remember(src_path, src_meta, src_hash, &previous_meta)?;
+        }
+        Ok(Some(src_meta.len()))
+    }
+
+    /// Copy a file to `temp_path`, which then replaces `dest_path`, with the preserved
+    /// attributes.
+    fn write_file(&self, src_path: &Path, temp_path: &Path, dest_path: &Path) -> Result<u64> {
+        let size = fs::copy(src_path, temp_path)
+            .with_context(|| format!("Failed to copy file to \"{}\"", dest_path.display()))?;
+        if self.options.preserve.any() {
+            metadata::apply(src_path, temp_path, &self.options.preserve).with_context(|| {
+                format!("Cannot preserve attributes of \"{}\"", dest_path.display())
+            })?;
+        }
+        Ok(size)
+    }
+
+    /// Move the current version of a destination file aside before it is overwritten.
+    fn back_up(&self, backup: &Backup, dest_path: &Path) -> Result<()> {
+        let (backup_path, removed) =
+            backup.save(&self.options.dest, dest_path, self.options.dry_run)?;
+        self.log.add(&format!(
+            "Backup {} to {}",
+            dest_path.display(),
+            backup_path.display()
+        ));
+        for path in removed.iter() {
+            self.log
+                .add(&format!("Delete old backup {}", path.display()));
+        }
+        Ok(())
+    }
+
+    /// Apply the `--conflict` policy to a destination file changed since it was copied. Returns
+    /// what was done if the file is kept, or `None` if the source should be copied over it.
+    fn resolve_conflict(
+        &self,
+        src_path: &Path,
+        dest_path: &Path,
+        size: u64,
+    ) -> Result<Option<(Action, u64)>> {
+        let kept = format!("Conflict {}, destination was changed", src_path.display());
+        match self.options.conflict {
+            ConflictPolicy::Skip => {
+                self.log.add(&kept);
+                Ok(Some((Action::Conflict, size)))
+            }
+            ConflictPolicy::Warn => {
+                eprintln!(
+                    "Warning: \"{}\" was changed since it was copied, not updated",
+                    dest_path.display()
+                );
+                self.log.add(&kept);
+                Ok(Some((Action::Conflict, size)))
+            }
+            ConflictPolicy::Overwrite => {
+                self.log.add(&format!(
+                    "Conflict {}, overwrite changed destination",
+                    src_path.display()
+                ));
+                Ok(None)
+            }
+            ConflictPolicy::Rename => {
+                let conflict_path = backup::conflict_path(dest_path);
+                self.log.add(&format!(
+                    "Conflict {}, move changed destination to {}",
+                    src_path.display(),
+                    conflict_path.display()
+                ));
+                if !self.options.dry_run {
+                    fs::rename(dest_path, &conflict_path).with_context(|| {
+                        format!("Cannot move \"{}\" out of the way", dest_path.display())
+                    })?;
+                }
+                Ok(None)
+            }
+            ConflictPolicy::Fail => bail!(
+                "Destination \"{}\" was changed since it was copied",
+                dest_path.display()
+            ),
+        }
+    }
+
+    /// Log a file that does not need to be copied.
+    fn unchanged(&self, src_path: &Path, size: u64, comparison: Comparison) -> (Action, u64) {
+        if let Comparison::Identical = comparison {
+            self.log.add(&format!("Same {}", src_path.display()));
+            (Action::Identical, size)
+        } else {
+            self.log.add(&format!("Old {}", src_path.display()));
+            (Action::Old, size)
+        }
+    }
+
+    /// Decide from the manifest whether a source file is unchanged since the previous run,
+    /// without looking at its copy unless `--check-dest` is set. Returns `None` if the manifest
+    /// cannot tell, and `Changed` if the copy was changed in the destination.
+    fn compare_with_manifest(
+        &self,
+        src_path: &Path,
+        src_meta: &fs::Metadata,
+        src_hash: Option<Checksum>,
+        dest_path: &Path,
+    ) -> Result<Option<Comparison>> {
+        let manifest = match &self.manifest {
+            Some(manifest) => manifest,
+            _ => return Ok(None),
+        };
+        let relative = src_path.strip_prefix(&self.options.src)?;
+        let entry = match manifest.get(relative) {
+            Some(entry) => entry,
+            _ => return Ok(None),
+        };
+        let comparison = match src_hash {
+            Some(hash)
+                if entry.size == src_meta.len()
+                    && entry.hash.as_deref() == Some(hash.to_hex().as_str()) =>
+            {
+                Comparison::Identical
+            }
+            None if entry.matches_source(src_meta) => Comparison::Older,
+            _ => return Ok(None),
+        };
+
+        if self.options.check_dest {
+            match dest_path.symlink_metadata() {
+                Ok(dest_meta) if entry.matches_copy(&dest_meta) => (),
+                Ok(_) => {
+                    self.log
+                        .add(&format!("Changed in destination {}", dest_path.display()));
+                    return Ok(Some(Comparison::Changed));
+                }
+                Err(_) => {
+                    self.log
+                        .add(&format!("Missing in destination {}", dest_path.display()));
+                    return Ok(Some(Comparison::Changed));
                 }
-            } else if !dest_dir.exists() {
-                fs::create_dir_all(&dest_dir).with_context(|| {
-                    format!("Cannot create directory \"{}\"", dest_dir.display())
-                })?;
             }
+        }
+        manifest.insert(relative, entry.clone());
+        Ok(Some(comparison))
+    }
+
+    /// Whether the copy of a file was changed in the destination since the manifest recorded it.
+    fn changed_since_copy(&self, src_path: &Path, dest_meta: &fs::Metadata) -> Result<bool> {
+        Ok(match &self.manifest {
+            Some(manifest) => manifest
+                .get(src_path.strip_prefix(&self.options.src)?)
+                .is_some_and(|entry| !entry.matches_copy(dest_meta)),
+            _ => false,
+        })
+    }
 
-            self.log.add(&format!("Copy {}", src_path.display()));
-            fs::copy(&src_path, &dest_path)
-                .with_context(|| format!("Failed to copy file to \"{}\"", dest_path.display()))
-                .map(|size| counter.count_copied(size))
+    /// Record the state of a file and its up-to-date copy in the manifest.
+    fn remember(
+        &self,
+        src_path: &Path,
+        src_meta: &fs::Metadata,
+        src_hash: Option<Checksum>,
+        dest_meta: &fs::Metadata,
+    ) -> Result<()> {
+        if let Some(manifest) = &self.manifest {
+            let hash = src_hash.map(|hash| hash.to_hex().to_string());
+            if let Some(entry) = Entry::new(src_meta, dest_meta, hash) {
+                manifest.insert(src_path.strip_prefix(&self.options.src)?, entry);
+            }
         }
+        Ok(())
+    }
+
+    fn compare(
+        &self,
+        src_path: &Path,
+        src_meta: &fs::Metadata,
+        src_hash: Option<Checksum>,
+        dest_path: &Path,
+        dest_meta: &fs::Metadata,
+    ) -> Result<Comparison> {
+        Ok(match self.options.compare {
+            CompareMode::Mtime | CompareMode::Size => {
+                self.compare_stat(src_meta.len(), src_meta.modified().ok(), dest_meta)
+            }
+            CompareMode::Hash => {
+                let src_hash = match src_hash {
+                    Some(hash) => hash,
+                    _ => checksum::file_hash(src_path)?,
+                };
+                if src_meta.len() == dest_meta.len() && src_hash == checksum::file_hash(dest_path)?
+                {
+                    Comparison::Identical
+                } else if self.dest_newer(src_meta.modified().ok(), dest_meta) {
+                    Comparison::Newer
+                } else {
+                    Comparison::Changed
+                }
+            }
+        })
+    }
+
+    /// Compare by modification time, and by size in `size` mode, without reading the contents.
+    fn compare_stat(
+        &self,
+        src_len: u64,
+        src_time: Option<SystemTime>,
+        dest_meta: &fs::Metadata,
+    ) -> Comparison {
+        let src_newer = match (src_time, dest_meta.modified()) {
+            (Some(src_time), Ok(dest_time)) => src_time > dest_time,
+            _ => false,
+        };
+        if self.dest_newer(src_time, dest_meta) {
+            Comparison::Newer
+        } else if src_newer
+            || (self.options.compare == CompareMode::Size && src_len != dest_meta.len())
+        {
+            Comparison::Changed
+        } else {
+            Comparison::Older
+        }
+    }
+
+    /// Whether the destination is strictly newer than the source. Copies only keep the time of
+    /// their source with `--preserve times`; otherwise they are always newer.
+    fn dest_newer(&self, src_time: Option<SystemTime>, dest_meta: &fs::Metadata) -> bool {
+        match (src_time, dest_meta.modified()) {
+            (Some(src_time), Ok(dest_time)) => self.options.preserve.times && dest_time > src_time,
+            _ => false,
+        }
+    }
+
+    fn copy_symlink(&self, src_path: &Path) -> Result<(Action, u64)> {
+        if self.options.symlinks == SymlinkPolicy::Skip {
+            self.log
+                .add(&format!("Skip symbolic link \"{}\"", src_path.display()));
+            return Ok((Action::Symlink, 0));
+        } else if self.options.symlinks == SymlinkPolicy::Follow {
+            self.log.add(&format!(
+                "Skip dangling symbolic link \"{}\"",
+                src_path.display()
+            ));
+            return Ok((Action::Symlink, 0));
+        }
+
+        let mut link_target = src_path.read_link()?;
+        if self.options.symlinks == SymlinkPolicy::Relative {
+            if let Some(relative) =
+                symlink::relative_target(&self.options.src, src_path, &link_target)
+            {
+                link_target = relative;
+            }
+        }
+
+        if self.options.archive.is_some() {
+            self.log.add(&format!(
+                "Add link {} -> {}",
+                src_path.display(),
+                link_target.display()
+            ));
+            if let Some(archive) = &self.archive {
+                archive.add_symlink(
+                    src_path.strip_prefix(&self.options.src)?,
+                    &link_target,
+                    src_path,
+                )?;
+            }
+            return Ok((Action::Symlink, 0));
+        }
+        self.write_symlink(src_path, &link_target)
+    }
+
+    /// Create the copy of the link at `src_path`, pointing to `link_target`, replacing what is
+    /// in its way in the destination.
+    fn write_symlink(&self, src_path: &Path, link_target: &Path) -> Result<(Action, u64)> {
+        let dest_path = self.dest_path_of(src_path)?;
+        match dest_path.symlink_metadata() {
+            Ok(dest_meta) if dest_meta.file_type().is_symlink() => {
+                if dest_path.read_link()? == link_target && !self.options.force_copy {
+                    self.log.add(&format!("Old {}", src_path.display()));
+                    return Ok((Action::Symlink, 0));
+                }
+                if !self.options.dry_run {
+                    fs::remove_file(&dest_path)?;
+                }
+            }
+            Ok(dest_meta) if dest_meta.is_dir() => {
+                bail!(
+                    "Cannot replace directory \"{}\" with a symbolic link",
+                    dest_path.display()
+                );
+            }
+            Ok(dest_meta) => {
+                if !self.options.dry_run {
+                    make_writable(&dest_path, &dest_meta)?;
+                    fs::remove_file(&dest_path)?;
+                }
+            }
+            Err(_) => self.create_parent_dir(&dest_path)?,
+        }
+
+        self.log.add(&format!(
+            "Link {} -> {}",
+            src_path.display(),
+            link_target.display()
+        ));
+        if !self.options.dry_run {
+            symlink::create(link_target, &dest_path).with_context(|| {
+                format!("Failed to create symbolic link \"{}\"", dest_path.display())
+            })?;
+        }
+        Ok((Action::Symlink, 0))
+    }
+
+    /// Remove entries of the destination tree that have no counterpart in the source (or that
+    /// are now ignored, if `--delete-excluded` is set). Everything below an orphaned directory
+    /// is orphaned as well.
+    fn prune_tree(
+        &self,
+        dest_path: &Path,
+        orphaned: bool,
+        rules: &DirRules,
+    ) -> Result<TypeCounter> {
+        let result = self.prune_path(dest_path, orphaned, rules);
+        self.recover(dest_path, result)
+    }
+
+    fn prune_path(
+        &self,
+        dest_path: &Path,
+        orphaned: bool,
+        rules: &DirRules,
+    ) -> Result<TypeCounter> {
+        let src_path = self
+            .options
+            .src
+            .join(dest_path.strip_prefix(&self.options.dest)?);
+        // Previous versions of files have no counterpart in the source on purpose, but they go
+        // with their directory once it is gone from the source
+        if !orphaned {
+            if let Some(backup) = &self.options.backup {
+                if backup.is_backup(&self.options.src, &self.options.dest, dest_path) {
+                    return Ok(TypeCounter::new());
+                }
+            }
+            if self.options.conflict == ConflictPolicy::Rename
+                && backup::is_conflict_copy(dest_path)
+            {
+                return Ok(TypeCounter::new());
+            }
+        }
+
+        let is_dir = !is_symlink(dest_path) && dest_path.is_dir();
+        let orphaned = orphaned
+            || src_path.symlink_metadata().is_err()
+            || (self.options.delete_excluded
+                && self.ignore_file.is_ignored_in(rules, &src_path, is_dir));
+
+        if is_dir {
+            let rules = if orphaned {
+                DirRules::empty()
+            } else {
+                self.ignore_file.dir_rules(&src_path, rules)?
+            };
+            let counter = dest_path
+                .read_dir()?
+                .map(|entry| self.prune_tree(entry?.path().as_path(), orphaned, &rules))
+                .collect::<Result<Vec<TypeCounter>>>()
+                .map(|v| v.iter().sum())?;
+            if orphaned {
+                self.log.add(&format!("Delete {}", dest_path.display()));
+                if !self.options.dry_run {
+                    fs::remove_dir(dest_path).with_context(|| {
+                        format!("Cannot delete directory \"{}\"", dest_path.display())
+                    })?;
+                }
+            }
+            Ok(counter)
+        } else if orphaned {
+            let meta = dest_path.symlink_metadata()?;
+            self.log.add(&format!("Delete {}", dest_path.display()));
+            if !self.options.dry_run {
+                if !meta.file_type().is_symlink() {
+                    make_writable(dest_path, &meta)?;
+                }
+                fs::remove_file(dest_path)
+                    .with_context(|| format!("Cannot delete file \"{}\"", dest_path.display()))?;
+            }
+            self.record(&src_path, Action::Deleted, meta.len(), None);
+            Ok(TypeCounter::new().count_deleted(meta.len()))
+        } else {
+            Ok(TypeCounter::new())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::app_options::AppOptions;
+    use super::archive::{self, ArchiveFormat, ArchiveWriter};
+    use super::report::Action;
+    use super::type_counter::TypeCounter;
+    use super::MyApp;
+    use filetime::FileTime;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::time::SystemTime;
+
+    fn options(src: &Path, dest: &Path, args: &[&str]) -> anyhow::Result<AppOptions> {
+        let mut args = args.to_vec();
+        args.extend(["--no-global-rules", "--exclude", "*.log"]);
+        args.extend([src.to_str().unwrap(), dest.to_str().unwrap()]);
+        AppOptions::parse(&args)
+    }
+
+    fn copy(src: &Path, dest: &Path, args: &[&str]) -> TypeCounter {
+        let app = MyApp::new(options(src, dest, args).unwrap()).unwrap();
+        let count = app.run().unwrap();
+        app.check_failures().unwrap();
+        count
+    }
+
+    /// Number of files counted under `action`.
+    fn number(count: &TypeCounter, action: Action) -> u64 {
+        count
+            .by_action()
+            .into_iter()
+            .find(|(counted, _, _)| *counted == action)
+            .map_or(0, |(_, number, _)| number)
+    }
+
+    /// Every path below `dir` with the content and modification time of the files.
+    fn snapshot(dir: &Path) -> Vec<(PathBuf, Option<Vec<u8>>, SystemTime)> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let meta = path.metadata().unwrap();
+            if meta.is_dir() {
+                entries.extend(snapshot(&path));
+                entries.push((path, None, meta.modified().unwrap()));
+            } else {
+                let content = fs::read(&path).unwrap();
+                entries.push((path, Some(content), meta.modified().unwrap()));
+            }
+        }
+        entries.sort();
+        entries
+    }
+
+    #[test]
+    fn mirror_deletes_directories_holding_backups() {
+        let temp = tempfile::tempdir().unwrap();
+        let (src, dest) = (&temp.path().join("src"), &temp.path().join("dest"));
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("sub/a.txt"), "1").unwrap();
+        fs::write(src.join("b.txt"), "1").unwrap();
+        copy(src, dest, &[]);
+        copy(src, dest, &["--backup", "-f"]);
+        assert!(dest.join("sub/a.txt.~1~").is_file());
+        assert!(dest.join("b.txt.~1~").is_file());
+
+        fs::remove_dir_all(src.join("sub")).unwrap();
+        copy(src, dest, &["--backup", "--mirror"]);
+        assert!(!dest.join("sub").exists());
+        assert!(dest.join("b.txt.~1~").is_file());
+    }
+
+    #[test]
+    fn mirror_deletes_files_merely_named_like_backups() {
+        let temp = tempfile::tempdir().unwrap();
+        let (src, dest) = (&temp.path().join("src"), &temp.path().join("dest"));
+        fs::create_dir_all(src).unwrap();
+        fs::write(src.join("a.txt"), "1").unwrap();
+        fs::write(src.join("notes~"), "1").unwrap();
+        copy(src, dest, &[]);
+        copy(src, dest, &["--backup=~", "-f"]);
+        assert!(dest.join("a.txt~").is_file());
+
+        fs::remove_file(src.join("notes~")).unwrap();
+        copy(src, dest, &["--backup=~", "--mirror"]);
+        assert!(dest.join("a.txt~").is_file());
+        assert!(!dest.join("notes~").exists());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn replacing_a_linked_file_leaves_its_snapshot_alone() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = tempfile::tempdir().unwrap();
+        let src = &temp.path().join("src");
+        let (first, second) = (&temp.path().join("first"), &temp.path().join("second"));
+        fs::create_dir_all(src).unwrap();
+        fs::write(src.join("a.txt"), "1").unwrap();
+        fs::set_permissions(src.join("a.txt"), fs::Permissions::from_mode(0o444)).unwrap();
+        copy(src, first, &[]);
+        copy(src, second, &["--link-dest", first.to_str().unwrap()]);
+
+        fs::set_permissions(src.join("a.txt"), fs::Permissions::from_mode(0o644)).unwrap();
+        fs::write(src.join("a.txt"), "22").unwrap();
+        copy(src, second, &["--compare", "size"]);
+        assert_eq!(fs::read_to_string(second.join("a.txt")).unwrap(), "22");
+        assert_eq!(fs::read_to_string(first.join("a.txt")).unwrap(), "1");
+        let mode = first.join("a.txt").metadata().unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o444);
+    }
+
+    #[test]
+    fn files_changed_on_both_sides_are_conflicts() {
+        let temp = tempfile::tempdir().unwrap();
+        let (src, dest) = (&temp.path().join("src"), &temp.path().join("dest"));
+        fs::create_dir_all(src).unwrap();
+        fs::write(src.join("a.txt"), "1").unwrap();
+        copy(src, dest, &["--manifest"]);
+
+        fs::write(src.join("a.txt"), "22").unwrap();
+        fs::write(dest.join("a.txt"), "edited").unwrap();
+        let count = copy(src, dest, &["--manifest", "--conflict", "rename"]);
+        assert!(count.to_string().contains("     1 file(s) in conflict"));
+        assert_eq!(fs::read_to_string(dest.join("a.txt")).unwrap(), "22");
+        assert_eq!(
+            fs::read_to_string(dest.join("a.txt.conflict")).unwrap(),
+            "edited"
+        );
+    }
+
+    #[test]
+    fn conflicts_cannot_be_detected_from_times_alone() {
+        let temp = tempfile::tempdir().unwrap();
+        let (src, dest) = (&temp.path().join("src"), &temp.path().join("dest"));
+        fs::create_dir_all(src).unwrap();
+        assert!(options(src, dest, &["--conflict", "fail"]).is_err());
+        assert!(options(src, dest, &["--conflict", "fail", "-p", "times"]).is_ok());
+        assert!(options(src, dest, &["--conflict", "fail", "--manifest"]).is_ok());
+    }
+
+    #[test]
+    fn temporary_files_of_interrupted_runs_are_deleted() {
+        let temp = tempfile::tempdir().unwrap();
+        let (src, dest) = (&temp.path().join("src"), &temp.path().join("dest"));
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("sub/a.txt"), "1").unwrap();
+        copy(src, dest, &[]);
+
+        let stale = dest.join(format!("sub/.slimcopy_tmp.{}.0", u32::MAX));
+        fs::write(&stale, "partial").unwrap();
+        copy(src, dest, &[]);
+        assert!(!stale.exists());
+        assert!(dest.join("sub/a.txt").is_file());
+    }
+
+    #[test]
+    fn archives_written_inside_the_source_are_left_out() {
+        let temp = tempfile::tempdir().unwrap();
+        let src = &temp.path().canonicalize().unwrap();
+        fs::write(src.join("a.txt"), "1").unwrap();
+        let dest = &src.join("slim.tar");
+        copy(src, dest, &[]);
+        // The archive of the previous run is there too this time
+        copy(src, dest, &[]);
+
+        let mut paths = Vec::new();
+        archive::read_entries(dest, ArchiveFormat::Tar, |entry| {
+            paths.push(entry.path);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(paths, vec![PathBuf::from("a.txt")]);
+    }
+
+    #[test]
+    fn dry_runs_leave_the_destination_alone() {
+        let temp = tempfile::tempdir().unwrap();
+        let (src, dest) = (&temp.path().join("src"), &temp.path().join("dest"));
+        fs::create_dir_all(src).unwrap();
+        fs::write(src.join("a.txt"), "1").unwrap();
+        copy(src, dest, &[]);
+        filetime::set_file_mtime(dest.join("a.txt"), FileTime::from_unix_time(1, 0)).unwrap();
+        fs::write(dest.join("stale.txt"), "1").unwrap();
+        fs::create_dir(src.join("sub")).unwrap();
+        fs::write(src.join("sub/b.txt"), "22").unwrap();
+
+        let before = snapshot(dest);
+        let count = copy(
+            src,
+            dest,
+            &["-n", "--mirror", "--backup", "--preserve", "times,mode"],
+        );
+        assert_eq!(snapshot(dest), before);
+        assert_eq!(number(&count, Action::Copied), 2);
+        assert_eq!(number(&count, Action::Deleted), 1);
+    }
+
+    #[test]
+    fn one_job_gives_the_same_totals() {
+        let temp = tempfile::tempdir().unwrap();
+        let src = &temp.path().join("src");
+        for dir in ["a", "a/b", "c"] {
+            fs::create_dir_all(src.join(dir)).unwrap();
+            for name in ["1.txt", "2.txt", "3.log"] {
+                fs::write(src.join(dir).join(name), dir).unwrap();
+            }
+        }
+
+        let parallel = copy(src, &temp.path().join("parallel"), &[]);
+        let sequential = copy(src, &temp.path().join("sequential"), &["-j", "1"]);
+        assert_eq!(parallel.by_action(), sequential.by_action());
+        assert_eq!(number(&sequential, Action::Copied), 6);
+        assert_eq!(number(&sequential, Action::Skipped), 3);
+    }
+
+    #[test]
+    fn compare_modes_decide_what_is_updated() {
+        let temp = tempfile::tempdir().unwrap();
+        let (src, dest) = (&temp.path().join("src"), &temp.path().join("dest"));
+        fs::create_dir_all(src).unwrap();
+        fs::write(src.join("a.txt"), "abc").unwrap();
+        fs::write(src.join("b.txt"), "abc").unwrap();
+        copy(src, dest, &[]);
+
+        // Changed content, but older than the copies
+        let old = FileTime::from_unix_time(1, 0);
+        fs::write(src.join("a.txt"), "longer").unwrap();
+        fs::write(src.join("b.txt"), "xyz").unwrap();
+        filetime::set_file_mtime(src.join("a.txt"), old).unwrap();
+        filetime::set_file_mtime(src.join("b.txt"), old).unwrap();
+
+        let count = copy(src, dest, &[]);
+        assert_eq!(number(&count, Action::Old), 2);
+        let count = copy(src, dest, &["--compare", "size"]);
+        assert_eq!(number(&count, Action::Copied), 1);
+        assert_eq!(number(&count, Action::Old), 1);
+        assert_eq!(fs::read_to_string(dest.join("a.txt")).unwrap(), "longer");
+        let count = copy(src, dest, &["--compare", "hash"]);
+        assert_eq!(number(&count, Action::Identical), 1);
+        assert_eq!(number(&count, Action::Copied), 1);
+        assert_eq!(fs::read_to_string(dest.join("b.txt")).unwrap(), "xyz");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn times_and_modes_are_preserved() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp = tempfile::tempdir().unwrap();
+        let (src, dest) = (&temp.path().join("src"), &temp.path().join("dest"));
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("sub/a.txt"), "1").unwrap();
+        fs::set_permissions(src.join("sub/a.txt"), fs::Permissions::from_mode(0o640)).unwrap();
+        fs::set_permissions(src.join("sub"), fs::Permissions::from_mode(0o750)).unwrap();
+        let (file_time, dir_time) = (
+            FileTime::from_unix_time(1_000_000, 0),
+            FileTime::from_unix_time(2_000_000, 0),
+        );
+        filetime::set_file_mtime(src.join("sub/a.txt"), file_time).unwrap();
+        filetime::set_file_mtime(src.join("sub"), dir_time).unwrap();
+
+        copy(src, dest, &["--preserve", "times,mode"]);
+        for (path, time, mode) in [("sub/a.txt", file_time, 0o640), ("sub", dir_time, 0o750)] {
+            let meta = dest.join(path).metadata().unwrap();
+            assert_eq!(
+                FileTime::from_last_modification_time(&meta),
+                time,
+                "{}",
+                path
+            );
+            assert_eq!(meta.permissions().mode() & 0o777, mode, "{}", path);
+        }
+    }
+
+    #[test]
+    fn failures_are_counted_and_skipped_with_keep_going() {
+        let temp = tempfile::tempdir().unwrap();
+        let (src, dest) = (&temp.path().join("src"), &temp.path().join("dest"));
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("sub/a.txt"), "1").unwrap();
+        fs::write(src.join("b.txt"), "1").unwrap();
+        // A file in the way of the directory of sub/a.txt
+        fs::create_dir(dest).unwrap();
+        fs::write(dest.join("sub"), "").unwrap();
+
+        let app = MyApp::new(options(src, dest, &["--keep-going"]).unwrap()).unwrap();
+        let count = app.run().unwrap();
+        assert_eq!(number(&count, Action::Error), 1);
+        assert_eq!(number(&count, Action::Copied), 1);
+        assert!(dest.join("b.txt").is_file());
+        let error = app.check_failures().unwrap_err();
+        assert!(format!("{:#}", error).contains("a.txt"));
+
+        let app = MyApp::new(options(src, dest, &[]).unwrap()).unwrap();
+        assert!(app.run().is_err());
+    }
+
+    #[test]
+    fn reports_list_every_file_with_the_totals() {
+        let temp = tempfile::tempdir().unwrap();
+        let (src, dest) = (&temp.path().join("src"), &temp.path().join("dest"));
+        fs::create_dir_all(src).unwrap();
+        fs::write(src.join("a.txt"), "12").unwrap();
+        fs::write(src.join("b.log"), "1").unwrap();
+        let (json, csv) = (
+            temp.path().join("report.json"),
+            temp.path().join("report.csv"),
+        );
+        copy(src, dest, &["--report", "json", json.to_str().unwrap()]);
+        fs::remove_dir_all(dest).unwrap();
+        copy(src, dest, &["--report", "csv", csv.to_str().unwrap()]);
+
+        let report: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&json).unwrap()).unwrap();
+        assert_eq!(report["totals"]["copied"], 1);
+        assert_eq!(report["totals"]["skipped"], 1);
+        let files = report["files"].as_array().unwrap();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0]["path"], "a.txt");
+        assert_eq!(files[0]["action"], "copied");
+        assert_eq!(files[0]["size"], 2);
+        assert_eq!(files[1]["path"], "b.log");
+        assert_eq!(files[1]["action"], "skipped");
+        assert!(files[1]["rule"].as_str().unwrap().contains("*.log"));
+
+        let csv = fs::read_to_string(&csv).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "path,action,count,size,rule");
+        assert_eq!(lines[1], "a.txt,copied,1,2,");
+        assert!(lines[2].starts_with("b.log,skipped,1,1,"));
+        assert!(lines.contains(&",copied,1,2,"));
+        assert!(lines.contains(&",skipped,1,1,"));
+    }
+
+    #[test]
+    fn reports_of_failed_runs_have_no_totals() {
+        let temp = tempfile::tempdir().unwrap();
+        let (src, dest) = (&temp.path().join("src"), &temp.path().join("dest"));
+        fs::create_dir_all(src.join("sub")).unwrap();
+        fs::write(src.join("sub/a.txt"), "1").unwrap();
+        fs::create_dir(dest).unwrap();
+        fs::write(dest.join("sub"), "").unwrap();
+        let json = temp.path().join("report.json");
+
+        let options = options(src, dest, &["--report", "json", json.to_str().unwrap()]);
+        assert!(MyApp::new(options.unwrap()).unwrap().run().is_err());
+        let report: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&json).unwrap()).unwrap();
+        assert!(report.get("totals").is_none());
+        assert_eq!(report["files"][0]["action"], "error");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn extracted_links_into_the_destination_become_relative() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = &temp.path().canonicalize().unwrap();
+        let (tar, dest) = (&dir.join("in.tar"), &dir.join("dest"));
+        fs::write(dir.join("file"), "").unwrap();
+        let writer = ArchiveWriter::create(tar, ArchiveFormat::Tar).unwrap();
+        let link = |name: &str, target: &Path| {
+            writer
+                .add_symlink(Path::new(name), target, &dir.join("file"))
+                .unwrap()
+        };
+        link("sub/inside", &dest.join("file"));
+        link("outside", Path::new("/etc/hosts"));
+        writer.finish().unwrap();
+
+        copy(tar, dest, &["--symlinks", "copy-link"]);
+        assert_eq!(
+            fs::read_link(dest.join("sub/inside")).unwrap(),
+            dest.join("file")
+        );
+        copy(tar, dest, &["--symlinks", "relative"]);
+        assert_eq!(
+            fs::read_link(dest.join("sub/inside")).unwrap(),
+            Path::new("../file")
+        );
+        assert_eq!(
+            fs::read_link(dest.join("outside")).unwrap(),
+            Path::new("/etc/hosts")
+        );
     }
 }
@@ -1,79 +1,743 @@
+use super::archive::ArchiveFormat;
+use super::backup::{Backup, Naming};
+use super::ignore_file::{global_rules_file, RULES_FILE_NAME};
+use super::metadata::Preserve;
+use super::report::ReportFormat;
 use anyhow::{bail, Context, Result};
-use clap::{clap_app, crate_version};
-use std::path::PathBuf;
+use clap::{clap_app, crate_version, App, AppSettings, Arg, ArgMatches};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    /// Do not copy symbolic links at all
+    Skip,
+    /// Recreate the link itself in the destination
+    CopyLink,
+    /// Copy what the link points to
+    Follow,
+    /// Like `CopyLink`, but absolute links pointing inside the source become relative
+    Relative,
+}
+
+impl FromStr for SymlinkPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "skip" => Ok(SymlinkPolicy::Skip),
+            "copy-link" => Ok(SymlinkPolicy::CopyLink),
+            "follow" => Ok(SymlinkPolicy::Follow),
+            "relative" => Ok(SymlinkPolicy::Relative),
+            _ => bail!("Unknown symbolic link policy \"{}\"", s),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompareMode {
+    /// Copy when the source is newer than the destination
+    Mtime,
+    /// Copy when the sizes differ or the source is newer
+    Size,
+    /// Copy when the contents differ
+    Hash,
+}
+
+impl FromStr for CompareMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "mtime" => Ok(CompareMode::Mtime),
+            "size" => Ok(CompareMode::Size),
+            "hash" => Ok(CompareMode::Hash),
+            _ => bail!("Unknown compare mode \"{}\"", s),
+        }
+    }
+}
+
+/// What to do with a destination file that was changed since it was copied, which is known from
+/// the manifest, or from its time being newer than the source's with `--preserve times`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Keep the destination file
+    Skip,
+    /// Replace it with the source file
+    Overwrite,
+    /// Keep it and print a warning
+    Warn,
+    /// Move it aside under a new name, then copy the source file
+    Rename,
+    /// Fail the file
+    Fail,
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "skip" => Ok(ConflictPolicy::Skip),
+            "overwrite" => Ok(ConflictPolicy::Overwrite),
+            "warn" => Ok(ConflictPolicy::Warn),
+            "rename" => Ok(ConflictPolicy::Rename),
+            "fail" => Ok(ConflictPolicy::Fail),
+            _ => bail!("Unknown conflict policy \"{}\"", s),
+        }
+    }
+}
+
+impl ConflictPolicy {
+    /// Whether the source file is copied over the conflicting one.
+    pub fn replaces(&self) -> bool {
+        matches!(self, ConflictPolicy::Overwrite | ConflictPolicy::Rename)
+    }
+}
+
+/// Options deciding which files are copied, shared by all commands.
+pub struct RuleOptions {
+    /// Rule files applied to the whole tree, in command line order
+    pub ignore_files: Vec<PathBuf>,
+    /// Whether no rule file was given, so that the rules file of the source is used if there is
+    /// one. It is looked for again whenever the rules are loaded.
+    pub default_rules_file: bool,
+    /// User-level rules applied before all others
+    pub global_rules: Option<PathBuf>,
+    /// Patterns given with `--exclude` and `--include`, in command line order
+    pub patterns: Vec<String>,
+    pub use_gitignore: bool,
+    pub include_file: Option<PathBuf>,
+}
+
+/// Options of `slimcopy check`, which explains which rules match the given paths.
+pub struct CheckOptions {
+    pub src: PathBuf,
+    pub rules: RuleOptions,
+    pub paths: Vec<PathBuf>,
+}
+
+/// Options of `slimcopy verify`, which checks an existing copy against its source.
+pub struct VerifyOptions {
+    pub src: PathBuf,
+    pub dest: PathBuf,
+    pub rules: RuleOptions,
+}
+
+/// Options of `slimcopy sync`, which brings two directories up to date with each other.
+pub struct SyncOptions {
+    pub a: PathBuf,
+    pub b: PathBuf,
+    pub rules: RuleOptions,
+    pub dry_run: bool,
+}
+
+/// Options of `slimcopy list`, which prints the paths the rules copy or ignore.
+pub struct ListOptions {
+    pub src: PathBuf,
+    pub rules: RuleOptions,
+    /// List the ignored paths rather than the copied ones
+    pub ignored: bool,
+    /// End paths with NUL rather than newline
+    pub null: bool,
+}
+
+/// Options of `slimcopy stats`, which sums up what the rules copy and ignore.
+pub struct StatsOptions {
+    pub src: PathBuf,
+    pub rules: RuleOptions,
+}
+
+pub enum Command {
+    Copy(AppOptions),
+    List(ListOptions),
+    Stats(StatsOptions),
+    Check(CheckOptions),
+    Verify(VerifyOptions),
+    Sync(SyncOptions),
+}
+
 pub struct AppOptions {
     pub src: PathBuf,
     pub dest: PathBuf,
-    pub ignore_file: PathBuf,
+    pub rules: RuleOptions,
     pub log_file: Option<PathBuf>,
     pub force_copy: bool,
+    pub dry_run: bool,
+    pub mirror: bool,
+    pub delete_excluded: bool,
+    pub symlinks: SymlinkPolicy,
+    pub jobs: usize,
+    pub compare: CompareMode,
+    pub conflict: ConflictPolicy,
+    pub preserve: Preserve,
+    pub explain: bool,
+    pub report: Option<(ReportFormat, PathBuf)>,
+    pub keep_going: bool,
+    pub manifest: bool,
+    pub check_dest: bool,
+    /// Previous snapshot to hard link unchanged files from
+    pub link_dest: Option<PathBuf>,
+    pub backup: Option<Backup>,
+    pub verify: bool,
+    /// Set if the destination is an archive file rather than a directory
+    pub archive: Option<ArchiveFormat>,
+    /// Set if the source is an archive file to extract from rather than a directory
+    pub src_archive: Option<ArchiveFormat>,
+    /// Keep copying changes after the first run
+    pub watch: bool,
 }
 
-impl AppOptions {
+impl Command {
     pub fn from_args() -> Result<Self> {
-        let matches = clap_app!(my_app =>
-            (version: crate_version!())
-            (author: "Kenneth Lo <closer.tw@gmail.com>")
-            (@arg SRC: +required +takes_value "Source directory")
-            (@arg DEST: +required +takes_value "Destination directory")
-            (@arg IGNORE_FILE: -i --("ignore-file") +takes_value "Reference ignored file")
+        // A bare `slimcopy SRC DEST` is a copy, as before there were subcommands
+        let matches = AppOptions::app()
+            .name("slimcopy")
+            .version(crate_version!())
+            .author("Kenneth Lo <closer.tw@gmail.com>")
+            .about("Copy a directory tree, leaving out the files matched by gitignore style rules")
+            .setting(AppSettings::SubcommandsNegateReqs)
+            .subcommand(AppOptions::app())
+            .subcommand(
+                clap_app!(list =>
+                    (about: "Print the paths of the source that would be copied, relative to it")
+                    (@arg SRC: +required +takes_value "Source directory")
+                    (@arg IGNORED: --ignored "Print the ignored paths instead")
+                )
+                .arg(
+                    Arg::with_name("NULL")
+                        .short("0")
+                        .long("null")
+                        .help("End paths with a NUL character, as xargs -0 expects"),
+                )
+                .args(&RuleOptions::args()),
+            )
+            .subcommand(
+                clap_app!(stats =>
+                    (about: "Count the files and sizes the rules copy and ignore, by rule")
+                    (@arg SRC: +required +takes_value "Source directory")
+                )
+                .args(&RuleOptions::args()),
+            )
+            .subcommand(
+                clap_app!(check =>
+                    (about: "Explain which rule decides whether the given paths are copied")
+                    (@arg SRC: +required +takes_value "Source directory")
+                    (@arg PATHS: +required +takes_value +multiple "Paths to check")
+                )
+                .args(&RuleOptions::args()),
+            )
+            .subcommand(
+                clap_app!(verify =>
+                    (about: "Check that the files of the source have identical copies in the destination")
+                    (@arg SRC: +required +takes_value "Source directory")
+                    (@arg DEST: +required +takes_value "Destination directory")
+                )
+                .args(&RuleOptions::args()),
+            )
+            .subcommand(
+                clap_app!(sync =>
+                    (about: "Copy the files changed since the last sync both ways between two directories")
+                    (@arg A: +required +takes_value "First directory, whose rule files are used")
+                    (@arg B: +required +takes_value "Second directory")
+                    (@arg DRY_RUN: -n --("dry-run") "Show what would be done without changing anything")
+                )
+                .args(&RuleOptions::args()),
+            )
+            .get_matches();
+
+        match matches.subcommand() {
+            ("copy", Some(matches)) => Ok(Command::Copy(AppOptions::from_matches(matches)?)),
+            ("list", Some(matches)) => Ok(Command::List(ListOptions::from_matches(matches)?)),
+            ("stats", Some(matches)) => Ok(Command::Stats(StatsOptions::from_matches(matches)?)),
+            ("check", Some(matches)) => Ok(Command::Check(CheckOptions::from_matches(matches)?)),
+            ("verify", Some(matches)) => Ok(Command::Verify(VerifyOptions::from_matches(matches)?)),
+            ("sync", Some(matches)) => Ok(Command::Sync(SyncOptions::from_matches(matches)?)),
+            _ => Ok(Command::Copy(AppOptions::from_matches(&matches)?)),
+        }
+    }
+}
+
+impl RuleOptions {
+    fn args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+        vec![
+            Arg::with_name("IGNORE_FILE")
+                .short("i")
+                .long("ignore-file")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .help("Reference ignored file; may be repeated, later files take precedence"),
+            Arg::with_name("NO_GLOBAL_RULES")
+                .long("no-global-rules")
+                .help("Ignore the user-level rules file"),
+            Arg::with_name("USE_GITIGNORE")
+                .long("use-gitignore")
+                .help("Also honour .gitignore and .ignore files in the source tree"),
+            Arg::with_name("INCLUDE_FILE")
+                .long("include-file")
+                .takes_value(true)
+                .help("Copy only the files matching the rules in this file"),
+            Arg::with_name("EXCLUDE")
+                .long("exclude")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("GLOB")
+                .help("Ignore the files matching this pattern"),
+            Arg::with_name("INCLUDE")
+                .long("include")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("GLOB")
+                .help("Copy the files matching this pattern, even if ignored by the rules"),
+        ]
+    }
+
+    fn from_matches(matches: &ArgMatches, src: &Path) -> Result<Self> {
+        // An `--include` pattern is a negated rule
+        let mut patterns = Vec::new();
+        for (name, prefix) in [("EXCLUDE", ""), ("INCLUDE", "!")] {
+            if let (Some(indices), Some(values)) =
+                (matches.indices_of(name), matches.values_of(name))
+            {
+                patterns.extend(indices.zip(values.map(|value| format!("{}{}", prefix, value))));
+            }
+        }
+        patterns.sort();
+        let patterns: Vec<String> = patterns.into_iter().map(|(_, pattern)| pattern).collect();
+
+        let global_rules = if matches.is_present("NO_GLOBAL_RULES") {
+            None
+        } else {
+            global_rules_file()
+        };
+
+        let include_file = match matches.value_of("INCLUDE_FILE") {
+            Some(value) => {
+                let path = PathBuf::from_str(value)?;
+                if !path.is_file() {
+                    bail!("Include file does not exist!")
+                }
+                Some(path)
+            }
+            _ => None,
+        };
+
+        let default_rules_file = !matches.is_present("IGNORE_FILE") && src.is_dir();
+        let ignore_files = match matches.values_of("IGNORE_FILE") {
+            Some(values) => {
+                let paths = values
+                    .map(PathBuf::from_str)
+                    .collect::<std::result::Result<Vec<_>, _>>()?;
+                if let Some(path) = paths.iter().find(|path| !path.is_file()) {
+                    bail!("Ignore file \"{}\" does not exist!", path.display())
+                }
+                paths
+            }
+            _ => {
+                let mut path = src.to_path_buf();
+                path.push(RULES_FILE_NAME);
+                // The rules file may only be left out when there are rules from elsewhere, or
+                // when extracting from an archive, which has no rules file next to its entries
+                if !src.is_dir() {
+                    Vec::new()
+                } else if path.is_file() {
+                    vec![path]
+                } else if patterns.is_empty() && global_rules.is_none() && include_file.is_none() {
+                    bail!("Ignore file does not exist!")
+                } else {
+                    Vec::new()
+                }
+            }
+        };
+
+        Ok(RuleOptions {
+            ignore_files,
+            default_rules_file,
+            global_rules,
+            patterns,
+            use_gitignore: matches.is_present("USE_GITIGNORE"),
+            include_file,
+        })
+    }
+}
+
+impl CheckOptions {
+    fn from_matches(matches: &ArgMatches) -> Result<Self> {
+        let src = source_dir(matches)?;
+        let rules = RuleOptions::from_matches(matches, &src)?;
+        let paths = matches
+            .values_of("PATHS")
+            .unwrap()
+            .map(PathBuf::from_str)
+            .collect::<std::result::Result<_, _>>()?;
+        Ok(CheckOptions { src, rules, paths })
+    }
+}
+
+impl ListOptions {
+    fn from_matches(matches: &ArgMatches) -> Result<Self> {
+        let src = source_dir(matches)?;
+        let rules = RuleOptions::from_matches(matches, &src)?;
+        Ok(ListOptions {
+            src,
+            rules,
+            ignored: matches.is_present("IGNORED"),
+            null: matches.is_present("NULL"),
+        })
+    }
+}
+
+impl StatsOptions {
+    fn from_matches(matches: &ArgMatches) -> Result<Self> {
+        let src = source_dir(matches)?;
+        let rules = RuleOptions::from_matches(matches, &src)?;
+        Ok(StatsOptions { src, rules })
+    }
+}
+
+impl VerifyOptions {
+    fn from_matches(matches: &ArgMatches) -> Result<Self> {
+        let src = source_dir(matches)?;
+        let dest = PathBuf::from_str(matches.value_of("DEST").unwrap())?
+            .canonicalize()
+            .context("Destination does not exist.")?;
+        let rules = RuleOptions::from_matches(matches, &src)?;
+        Ok(VerifyOptions { src, dest, rules })
+    }
+}
+
+impl SyncOptions {
+    fn from_matches(matches: &ArgMatches) -> Result<Self> {
+        let mut dirs = Vec::new();
+        for name in ["A", "B"] {
+            let value = matches.value_of(name).unwrap();
+            let dir = PathBuf::from_str(value)?
+                .canonicalize()
+                .with_context(|| format!("\"{}\" does not exist.", value))?;
+            if !dir.is_dir() {
+                bail!("\"{}\" must be a directory.", value);
+            }
+            dirs.push(dir);
+        }
+        let b = dirs.pop().unwrap();
+        let a = dirs.pop().unwrap();
+        if a.starts_with(&b) || b.starts_with(&a) {
+            bail!("Cannot sync a directory with itself or one inside it.");
+        }
+        let rules = RuleOptions::from_matches(matches, &a)?;
+        Ok(SyncOptions {
+            a,
+            b,
+            rules,
+            dry_run: matches.is_present("DRY_RUN"),
+        })
+    }
+}
+
+/// Canonical path of the source directory given on the command line.
+fn source_dir(matches: &ArgMatches) -> Result<PathBuf> {
+    let src = PathBuf::from_str(matches.value_of("SRC").unwrap())?
+        .canonicalize()
+        .context("Source does not exist.")?;
+    if !src.is_dir() {
+        bail!("Source must be a directory.");
+    }
+    Ok(src)
+}
+
+impl AppOptions {
+    /// Arguments of a copy, taken by `slimcopy copy` as well as by a bare `slimcopy`.
+    fn app<'a, 'b>() -> App<'a, 'b> {
+        clap_app!(copy =>
+            (about: "Copy the files of the source that the rules do not ignore")
+            (@arg SRC: +required +takes_value "Source directory, or .tar, .tar.gz, .tar.zst or .zip archive")
+            (@arg DEST: +required +takes_value "Destination directory, or .tar, .tar.gz, .tar.zst or .zip archive")
+            (@arg REPORT: --report +takes_value number_of_values(2) value_names(&["FORMAT", "PATH"])
+                "Write a report of all processed files as json or csv")
             (@arg LOG_FILE: --log +takes_value "Log to file")
+            (@arg EXPLAIN: --explain "Log the rule responsible for each skipped file")
             (@arg FORCE_COPY: -f --("force-copy") "Force")
+            (@arg DRY_RUN: -n --("dry-run") "Show what would be copied without touching the destination")
+            (@arg KEEP_GOING: -k --("keep-going") "Continue with the remaining files when a file cannot be copied")
+            (@arg MIRROR: --mirror alias("delete") "Delete files in destination that do not exist in source")
+            (@arg SYMLINKS: --symlinks +takes_value
+                possible_values(&["skip", "copy-link", "follow", "relative"]) default_value("skip")
+                "How to handle symbolic links")
+            (@arg COMPARE: --compare +takes_value
+                possible_values(&["mtime", "size", "hash"]) default_value("mtime")
+                "How to decide whether an existing file needs to be updated")
+            (@arg CONFLICT: --conflict +takes_value
+                possible_values(&["skip", "overwrite", "warn", "rename", "fail"]) default_value("skip")
+                "What to do with destination files changed since they were copied")
+            (@arg PRESERVE: -p --preserve +takes_value +use_delimiter
+                possible_values(&["times", "mode", "owner", "xattr", "all"])
+                "Preserve the given attributes of copied files and directories")
+            (@arg JOBS: -j --jobs +takes_value "Number of files to copy in parallel [default: number of CPUs]")
+            (@arg WATCH: --watch "After copying, keep copying changed files until interrupted")
+            (@arg VERIFY: --verify "Check that the copied files match the source once copied")
+            (@arg MANIFEST: --manifest "Keep a manifest of copied files in destination to skip unchanged files faster")
+            (@arg CHECK_DEST: --("check-dest") requires("MANIFEST") "Also recopy files changed or deleted in destination since the last run")
+            (@arg LINK_DEST: --("link-dest") +takes_value "Hard link files unchanged since this previous snapshot instead of copying them")
+            (@arg BACKUP: --backup +takes_value min_values(0) require_equals(true) value_name("SUFFIX")
+                "Keep the previous version of overwritten files, numbered or with the given suffix")
+            (@arg BACKUP_DIR: --("backup-dir") +takes_value "Keep the previous versions in this directory (implies --backup)")
+            (@arg BACKUP_KEEP: --("backup-keep") +takes_value "Number of numbered previous versions to keep for each file")
+            (@arg DELETE_EXCLUDED: --("delete-excluded") requires("MIRROR") "Also delete files in destination that are ignored by the rules")
         )
-        .get_matches();
+        .args(&RuleOptions::args())
+    }
 
-        let src = PathBuf::from_str(matches.value_of("SRC").unwrap())?
-            .canonicalize()
-            .context("Source does not exist.")?;
-        if !src.is_dir() {
-            bail!("Source must be a directory.");
-        }
+    /// Options of a copy with the given command line arguments.
+    #[cfg(test)]
+    pub fn parse(args: &[&str]) -> Result<Self> {
+        let matches = Self::app()
+            .get_matches_from_safe(std::iter::once("copy").chain(args.iter().copied()))?;
+        Self::from_matches(&matches)
+    }
+
+    fn from_matches(matches: &ArgMatches) -> Result<Self> {
+        let src = PathBuf::from_str(matches.value_of("SRC").unwrap())?;
+        let src_archive = ArchiveFormat::from_path(&src).filter(|_| src.is_file());
+        let src = if src_archive.is_some() {
+            src.canonicalize()?
+        } else {
+            source_dir(matches)?
+        };
+
+        let dry_run = matches.is_present("DRY_RUN");
 
         let dest = PathBuf::from_str(matches.value_of("DEST").unwrap())?;
-        if !dest.exists() {
-            if let Some(true) = dest.parent().map(|parent| parent.exists()) {
-                // create directory
-                std::fs::create_dir(&dest).with_context(|| {
-                    format!(
-                        "Failed to create destination directory \"{}\"",
-                        dest.display()
-                    )
-                })?;
+        let archive = ArchiveFormat::from_path(&dest).filter(|_| !dest.is_dir());
+        let dest = if archive.is_some() {
+            Self::absolute_path(&dest).context("Destination directory does not exist.")?
+        } else if !dest.exists() {
+            let parent_exists = dest
+                .parent()
+                .map(|parent| parent.as_os_str().is_empty() || parent.exists());
+            if let Some(true) = parent_exists {
+                if dry_run {
+                    // Do not create anything; just resolve where it would be created
+                    Self::absolute_path(&dest)?
+                } else {
+                    // create directory
+                    std::fs::create_dir(&dest).with_context(|| {
+                        format!(
+                            "Failed to create destination directory \"{}\"",
+                            dest.display()
+                        )
+                    })?;
+                    dest.canonicalize()?
+                }
             } else {
                 bail!("Destination does not exist.");
             }
         } else if !dest.is_dir() {
             bail!("Destination must be a directory.");
+        } else {
+            dest.canonicalize()?
+        };
+
+        let rules = RuleOptions::from_matches(matches, &src)?;
+        if let Some(path) = &rules.global_rules {
+            println!("Global rules = {}", path.display());
+        }
+        if rules.ignore_files.is_empty() {
+            println!("Ignore file = none");
+        }
+        for path in rules.ignore_files.iter() {
+            println!("Ignore file = {}", path.display());
         }
-        let dest = dest.canonicalize()?;
 
-        let ignore_file = match matches.value_of("IGNORE_FILE") {
-            Some(value) => PathBuf::from_str(value)?,
-            _ => {
-                let mut path = src.clone();
-                path.push(".slimcopy_rules");
-                path
+        let log_file = matches
+            .value_of("LOG_FILE")
+            .and_then(|path| PathBuf::from_str(path).ok());
+
+        let jobs = match matches.value_of("JOBS") {
+            Some(value) => value
+                .parse()
+                .with_context(|| format!("Invalid number of jobs \"{}\"", value))?,
+            _ => 0,
+        };
+
+        let report = match matches.values_of("REPORT") {
+            Some(mut values) => {
+                let format = values.next().unwrap().parse()?;
+                Some((format, PathBuf::from_str(values.next().unwrap())?))
+            }
+            _ => None,
+        };
+
+        let link_dest = match matches.value_of("LINK_DEST") {
+            Some(value) => {
+                let path = PathBuf::from_str(value)?
+                    .canonicalize()
+                    .context("Link destination does not exist.")?;
+                if !path.is_dir() {
+                    bail!("Link destination must be a directory.");
+                }
+                if path == dest {
+                    bail!("Link destination must differ from the destination.");
+                }
+                Some(path)
             }
+            _ => None,
         };
 
-        if !ignore_file.exists() || !ignore_file.is_file() {
-            bail!("Ignore file does not exist!")
+        let backup = if matches.is_present("BACKUP") || matches.is_present("BACKUP_DIR") {
+            let naming = match matches.value_of("BACKUP") {
+                Some(suffix) if !suffix.is_empty() => Naming::Suffix(suffix.to_string()),
+                _ => Naming::Numbered,
+            };
+            let keep = match matches.value_of("BACKUP_KEEP") {
+                Some(value) => match value.parse() {
+                    Ok(keep) if keep > 0 => Some(keep),
+                    _ => bail!("Invalid number of backups to keep \"{}\"", value),
+                },
+                _ => None,
+            };
+            if keep.is_some() && naming != Naming::Numbered {
+                bail!("--backup-keep only applies to numbered backups.");
+            }
+            Some(Backup {
+                naming,
+                dir: matches.value_of("BACKUP_DIR").map(PathBuf::from),
+                keep,
+            })
+        } else {
+            None
+        };
+
+        let mut preserve = Preserve::default();
+        for value in matches.values_of("PRESERVE").into_iter().flatten() {
+            let all = value == "all";
+            preserve.times |= all || value == "times";
+            preserve.mode |= all || value == "mode";
+            preserve.owner |= all || value == "owner";
+            preserve.xattr |= all || value == "xattr";
         }
-        println!("Ignore file = {}", ignore_file.display());
 
-        let log_file = matches
-            .value_of("LOG_FILE")
-            .map(|path| PathBuf::from_str(path).ok())
-            .flatten();
+        if archive.is_some() {
+            for (name, option) in [
+                ("MIRROR", "--mirror"),
+                ("MANIFEST", "--manifest"),
+                ("LINK_DEST", "--link-dest"),
+                ("BACKUP", "--backup"),
+                ("BACKUP_DIR", "--backup-dir"),
+                ("VERIFY", "--verify"),
+                ("WATCH", "--watch"),
+            ] {
+                if matches.is_present(name) {
+                    bail!("{} cannot be used when writing an archive.", option);
+                }
+            }
+        }
+
+        if src_archive.is_some() {
+            if archive.is_some() {
+                bail!("Cannot write an archive from an archive.");
+            }
+            if matches.value_of("COMPARE") == Some("hash") {
+                bail!("--compare hash cannot be used when reading an archive.");
+            }
+            for (name, option) in [
+                ("MIRROR", "--mirror"),
+                ("MANIFEST", "--manifest"),
+                ("LINK_DEST", "--link-dest"),
+                ("VERIFY", "--verify"),
+                ("WATCH", "--watch"),
+            ] {
+                if matches.is_present(name) {
+                    bail!("{} cannot be used when reading an archive.", option);
+                }
+            }
+        }
+
+        // Without either, copies are always newer than their source and look like conflicts
+        if matches.occurrences_of("CONFLICT") > 0
+            && !preserve.times
+            && !matches.is_present("MANIFEST")
+        {
+            bail!("--conflict needs --preserve times or --manifest to detect conflicts.");
+        }
+
+        if matches.is_present("WATCH") {
+            for (name, option) in [("DRY_RUN", "--dry-run"), ("REPORT", "--report")] {
+                if matches.is_present(name) {
+                    bail!("{} cannot be used with --watch.", option);
+                }
+            }
+        }
 
         Ok(AppOptions {
             src,
             dest,
-            ignore_file,
+            rules,
             log_file,
             force_copy: matches.is_present("FORCE_COPY"),
+            dry_run,
+            mirror: matches.is_present("MIRROR"),
+            delete_excluded: matches.is_present("DELETE_EXCLUDED"),
+            symlinks: matches.value_of("SYMLINKS").unwrap().parse()?,
+            jobs,
+            compare: matches.value_of("COMPARE").unwrap().parse()?,
+            conflict: matches.value_of("CONFLICT").unwrap().parse()?,
+            preserve,
+            explain: matches.is_present("EXPLAIN"),
+            report,
+            keep_going: matches.is_present("KEEP_GOING"),
+            manifest: matches.is_present("MANIFEST"),
+            check_dest: matches.is_present("CHECK_DEST"),
+            link_dest,
+            backup,
+            verify: matches.is_present("VERIFY"),
+            archive,
+            src_archive,
+            watch: matches.is_present("WATCH"),
         })
     }
+
+    /// Resolve a path that may not exist yet by canonicalizing its parent.
+    fn absolute_path(path: &Path) -> Result<PathBuf> {
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let name = path.file_name().context("Invalid destination path.")?;
+        Ok(parent.canonicalize()?.join(name))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RuleOptions;
+    use crate::my_app::ignore_file::{DirRules, IgnoreFile};
+    use clap::App;
+
+    #[test]
+    fn include_file_is_enough_without_rules_file() {
+        let temp = tempfile::tempdir().unwrap();
+        let src = temp.path().join("src");
+        std::fs::create_dir(&src).unwrap();
+        let include_file = temp.path().join("list");
+        std::fs::write(&include_file, "*.rs\n").unwrap();
+
+        let matches = App::new("test")
+            .args(&RuleOptions::args())
+            .get_matches_from(vec![
+                "test",
+                "--no-global-rules",
+                "--include-file",
+                include_file.to_str().unwrap(),
+            ]);
+        let options = RuleOptions::from_matches(&matches, &src).unwrap();
+        assert!(options.ignore_files.is_empty());
+
+        let file = IgnoreFile::load(&src, &options).unwrap();
+        let top = DirRules::empty();
+        assert!(!file.is_ignored_in(&top, src.join("main.rs"), false));
+        assert!(file.is_ignored_in(&top, src.join("main.txt"), false));
+    }
 }
@@ -0,0 +1,477 @@
+use anyhow::{bail, Context, Result};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+/// Kind of archive read or written when the source or destination is an archive file, chosen
+/// from its extension.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Tar,
+    TarGz,
+    TarZst,
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// Recognise `.tar`, `.tar.gz` (`.tgz`), `.tar.zst` (`.tzst`) and `.zip` files.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?.to_lowercase();
+        if name.ends_with(".tar") {
+            Some(ArchiveFormat::Tar)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(ArchiveFormat::TarGz)
+        } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+            Some(ArchiveFormat::TarZst)
+        } else if name.ends_with(".zip") {
+            Some(ArchiveFormat::Zip)
+        } else {
+            None
+        }
+    }
+}
+
+/// Compression applied to a tar stream.
+enum Encoder {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+impl Encoder {
+    fn finish(self) -> io::Result<()> {
+        let mut file = match self {
+            Encoder::Plain(file) => file,
+            Encoder::Gzip(encoder) => encoder.finish()?,
+            Encoder::Zstd(encoder) => encoder.finish()?,
+        };
+        file.flush()
+    }
+}
+
+impl Write for Encoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Plain(file) => file.write(buf),
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Plain(file) => file.flush(),
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+enum Writer {
+    Tar(tar::Builder<Encoder>),
+    Zip(ZipWriter<BufWriter<File>>),
+}
+
+/// Temporary name of the archive at `path` while it is written.
+fn part_path(path: &Path) -> PathBuf {
+    let mut part_name = path.file_name().unwrap().to_os_string();
+    part_name.push(".part");
+    path.with_file_name(part_name)
+}
+
+/// Whether `path` is the archive written to `archive_path`, finished or not, which must not be
+/// added to itself when it is written inside the source.
+pub fn is_archive_file(archive_path: &Path, path: &Path) -> bool {
+    path == archive_path || path == part_path(archive_path)
+}
+
+/// Archive receiving the copied files instead of a destination directory. Entries are added
+/// one at a time, from any thread. The archive is written under a temporary name and only
+/// takes its final name once finished.
+pub struct ArchiveWriter {
+    path: PathBuf,
+    part_path: PathBuf,
+    writer: Mutex<Option<Writer>>,
+}
+
+impl ArchiveWriter {
+    pub fn create(path: &Path, format: ArchiveFormat) -> Result<Self> {
+        let part_path = part_path(path);
+        let file = BufWriter::new(
+            File::create(&part_path)
+                .with_context(|| format!("Cannot create archive \"{}\"", path.display()))?,
+        );
+
+        let writer = match format {
+            ArchiveFormat::Zip => Writer::Zip(ZipWriter::new(file)),
+            _ => {
+                let encoder = match format {
+                    ArchiveFormat::TarGz => {
+                        Encoder::Gzip(GzEncoder::new(file, flate2::Compression::default()))
+                    }
+                    ArchiveFormat::TarZst => Encoder::Zstd(zstd::Encoder::new(file, 0)?),
+                    _ => Encoder::Plain(file),
+                };
+                let mut builder = tar::Builder::new(encoder);
+                // Followed symbolic links are added by the caller as regular files
+                builder.follow_symlinks(true);
+                Writer::Tar(builder)
+            }
+        };
+
+        Ok(ArchiveWriter {
+            path: path.to_path_buf(),
+            part_path,
+            writer: Mutex::new(Some(writer)),
+        })
+    }
+
+    /// Add a directory entry, so that empty directories are kept.
+    pub fn add_dir(&self, name: &Path, src_path: &Path) -> Result<()> {
+        let meta = src_path.metadata()?;
+        self.with_writer(|writer| match writer {
+            Writer::Tar(builder) => Ok(builder.append_dir(name, src_path)?),
+            Writer::Zip(zip) => Ok(zip.add_directory(entry_name(name), zip_options(&meta))?),
+        })
+    }
+
+    /// Add the content of a file, with its modification time and permissions. Returns its size.
+    pub fn add_file(&self, name: &Path, src_path: &Path) -> Result<u64> {
+        let meta = src_path.metadata()?;
+        self.with_writer(|writer| match writer {
+            Writer::Tar(builder) => {
+                builder.append_path_with_name(src_path, name)?;
+                Ok(meta.len())
+            }
+            Writer::Zip(zip) => {
+                let options = zip_options(&meta).large_file(meta.len() >= u32::MAX as u64);
+                zip.start_file(entry_name(name), options)?;
+                Ok(io::copy(&mut File::open(src_path)?, zip)?)
+            }
+        })
+    }
+
+    /// Add a symbolic link pointing to `target`.
+    pub fn add_symlink(&self, name: &Path, target: &Path, src_path: &Path) -> Result<()> {
+        let meta = src_path.symlink_metadata()?;
+        self.with_writer(|writer| match writer {
+            Writer::Tar(builder) => {
+                let mut header = tar::Header::new_gnu();
+                header.set_metadata(&meta);
+                header.set_entry_type(tar::EntryType::Symlink);
+                header.set_size(0);
+                Ok(builder.append_link(&mut header, name, target)?)
+            }
+            Writer::Zip(zip) => {
+                Ok(zip.add_symlink(entry_name(name), entry_name(target), zip_options(&meta))?)
+            }
+        })
+    }
+
+    /// Complete the archive and give it its final name.
+    pub fn finish(&self) -> Result<()> {
+        match self.writer.lock().unwrap().take() {
+            Some(Writer::Tar(builder)) => builder.into_inner()?.finish()?,
+            Some(Writer::Zip(mut zip)) => zip.finish()?.flush()?,
+            None => bail!("Archive \"{}\" is already closed", self.path.display()),
+        }
+        fs::rename(&self.part_path, &self.path)
+            .with_context(|| format!("Cannot create archive \"{}\"", self.path.display()))
+    }
+
+    /// Drop an unfinished archive.
+    pub fn discard(&self) {
+        self.writer.lock().unwrap().take();
+        let _ = fs::remove_file(&self.part_path);
+    }
+
+    fn with_writer<T, F: FnOnce(&mut Writer) -> Result<T>>(&self, f: F) -> Result<T> {
+        match self.writer.lock().unwrap().as_mut() {
+            Some(writer) => f(writer),
+            None => bail!("Archive \"{}\" is already closed", self.path.display()),
+        }
+    }
+}
+
+/// What an archive entry is.
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink(PathBuf),
+    /// Hard links, devices, ...
+    Other,
+}
+
+/// An entry read from an archive, with a reader for its content.
+pub struct Entry<'a> {
+    /// Path of the entry as stored in the archive, see [`safe_path`]
+    pub path: PathBuf,
+    pub kind: EntryKind,
+    pub size: u64,
+    pub mtime: Option<SystemTime>,
+    pub mode: Option<u32>,
+    pub reader: &'a mut dyn Read,
+}
+
+/// Call `f` for each entry of the archive at `path`, in the order they are stored.
+pub fn read_entries<F>(path: &Path, format: ArchiveFormat, f: F) -> Result<()>
+where
+    F: FnMut(Entry) -> Result<()>,
+{
+    let file = BufReader::new(
+        File::open(path).with_context(|| format!("Cannot open archive \"{}\"", path.display()))?,
+    );
+    match format {
+        ArchiveFormat::Tar => read_tar(file, f),
+        ArchiveFormat::TarGz => read_tar(GzDecoder::new(file), f),
+        ArchiveFormat::TarZst => read_tar(zstd::Decoder::with_buffer(file)?, f),
+        ArchiveFormat::Zip => read_zip(file, f),
+    }
+}
+
+fn read_tar<R: Read, F: FnMut(Entry) -> Result<()>>(reader: R, mut f: F) -> Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let header = entry.header();
+        let kind = match header.entry_type() {
+            tar::EntryType::Regular | tar::EntryType::Continuous => EntryKind::File,
+            tar::EntryType::Directory => EntryKind::Dir,
+            tar::EntryType::Symlink => match entry.link_name()? {
+                Some(target) => EntryKind::Symlink(target.into_owned()),
+                _ => EntryKind::Other,
+            },
+            _ => EntryKind::Other,
+        };
+        let mtime = header
+            .mtime()
+            .ok()
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+        let mode = header.mode().ok();
+        let path = entry.path()?.into_owned();
+        let size = entry.size();
+        f(Entry {
+            path,
+            kind,
+            size,
+            mtime,
+            mode,
+            reader: &mut entry,
+        })?;
+    }
+    Ok(())
+}
+
+fn read_zip<R: Read + io::Seek, F: FnMut(Entry) -> Result<()>>(reader: R, mut f: F) -> Result<()> {
+    let mut archive = ZipArchive::new(reader)?;
+    for index in 0..archive.len() {
+        let mut file = archive.by_index(index)?;
+        let mode = file.unix_mode();
+        let kind = if file.is_dir() {
+            EntryKind::Dir
+        } else if mode.is_some_and(|mode| mode & 0o170000 == 0o120000) {
+            // The content of a link is its target
+            let mut target = String::new();
+            file.read_to_string(&mut target)?;
+            EntryKind::Symlink(PathBuf::from(target))
+        } else {
+            EntryKind::File
+        };
+        let path = PathBuf::from(file.name());
+        let size = file.size();
+        let mtime = from_zip_time(file.last_modified());
+        f(Entry {
+            path,
+            kind,
+            size,
+            mtime,
+            mode: mode.map(|mode| mode & 0o7777),
+            reader: &mut file,
+        })?;
+    }
+    Ok(())
+}
+
+/// The path of an entry if it stays inside the directory it is extracted to, without its
+/// leading `./`. Entries with absolute paths or `..` components are not extracted.
+pub fn safe_path(path: &Path) -> Option<PathBuf> {
+    let mut safe = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => safe.push(name),
+            Component::CurDir => (),
+            _ => return None,
+        }
+    }
+    if safe.as_os_str().is_empty() {
+        None
+    } else {
+        Some(safe)
+    }
+}
+
+/// Names of zip entries always use `/` as separator.
+fn entry_name(path: &Path) -> String {
+    path.to_string_lossy().replace('\\', "/")
+}
+
+fn zip_options(meta: &fs::Metadata) -> FileOptions {
+    let mut options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    if let Some(time) = meta.modified().ok().and_then(zip_time) {
+        options = options.last_modified_time(time);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        options = options.unix_permissions(meta.permissions().mode() & 0o7777);
+    }
+    options
+}
+
+/// Convert a time to the date and time stored in zip entries, in UTC. Zip files cannot store
+/// times before 1980.
+fn zip_time(time: SystemTime) -> Option<zip::DateTime> {
+    let secs = time.duration_since(UNIX_EPOCH).ok()?.as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+
+    // Civil date from days since 1970-01-01, from Howard Hinnant's `civil_from_days`
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    zip::DateTime::from_date_and_time(
+        u16::try_from(year).ok()?,
+        month as u8,
+        day as u8,
+        (secs / 3600) as u8,
+        (secs % 3600 / 60) as u8,
+        (secs % 60) as u8,
+    )
+    .ok()
+}
+
+/// Convert the date and time of a zip entry back to a time, from Howard Hinnant's
+/// `days_from_civil`.
+fn from_zip_time(time: zip::DateTime) -> Option<SystemTime> {
+    let month = i64::from(time.month());
+    let year = i64::from(time.year()) - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + i64::from(time.day()) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    let secs = days * 86400
+        + i64::from(time.hour()) * 3600
+        + i64::from(time.minute()) * 60
+        + i64::from(time.second());
+    Some(UNIX_EPOCH + Duration::from_secs(u64::try_from(secs).ok()?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{from_zip_time, read_entries, safe_path, zip_time, ArchiveFormat, ArchiveWriter};
+    use std::fs;
+    use std::path::Path;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn formats_are_recognised_by_extension() {
+        let format = |name: &str| ArchiveFormat::from_path(Path::new(name));
+        assert_eq!(format("out/slim.tar"), Some(ArchiveFormat::Tar));
+        assert_eq!(format("slim.tar.gz"), Some(ArchiveFormat::TarGz));
+        assert_eq!(format("slim.TGZ"), Some(ArchiveFormat::TarGz));
+        assert_eq!(format("slim.tar.zst"), Some(ArchiveFormat::TarZst));
+        assert_eq!(format("slim.zip"), Some(ArchiveFormat::Zip));
+        assert_eq!(format("slim"), None);
+        assert_eq!(format("slim.gz"), None);
+    }
+
+    #[test]
+    fn times_are_converted_to_dates() {
+        // 2024-02-29 13:45:30 UTC
+        let time = zip_time(UNIX_EPOCH + Duration::from_secs(1709214330)).unwrap();
+        assert_eq!(
+            (
+                time.year(),
+                time.month(),
+                time.day(),
+                time.hour(),
+                time.minute(),
+                time.second()
+            ),
+            (2024, 2, 29, 13, 45, 30)
+        );
+        assert!(zip_time(UNIX_EPOCH).is_none());
+    }
+
+    #[test]
+    fn times_survive_a_round_trip() {
+        let time = UNIX_EPOCH + Duration::from_secs(1709214330);
+        assert_eq!(from_zip_time(zip_time(time).unwrap()), Some(time));
+    }
+
+    #[test]
+    fn unsafe_paths_are_rejected() {
+        assert_eq!(
+            safe_path(Path::new("./a/b")),
+            Some(Path::new("a/b").to_path_buf())
+        );
+        assert_eq!(safe_path(Path::new("a/../../b")), None);
+        assert_eq!(safe_path(Path::new("/etc/passwd")), None);
+        assert_eq!(safe_path(Path::new("./")), None);
+    }
+
+    #[test]
+    fn written_archives_are_read_back() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let file = dir.join("a.txt");
+        fs::write(&file, "content").unwrap();
+
+        for (name, format) in [
+            ("t.tar.gz", ArchiveFormat::TarGz),
+            ("t.zip", ArchiveFormat::Zip),
+        ] {
+            let path = dir.join(name);
+            let writer = ArchiveWriter::create(&path, format).unwrap();
+            writer.add_dir(Path::new("sub"), dir).unwrap();
+            writer.add_file(Path::new("sub/a.txt"), &file).unwrap();
+            writer.finish().unwrap();
+
+            let mut entries = Vec::new();
+            read_entries(&path, format, |entry| {
+                let mut content = String::new();
+                entry.reader.read_to_string(&mut content)?;
+                entries.push((entry.path, entry.size, content));
+                Ok(())
+            })
+            .unwrap();
+            assert_eq!(entries.len(), 2);
+            assert_eq!(
+                safe_path(&entries[0].0),
+                Some(Path::new("sub").to_path_buf())
+            );
+            assert_eq!(
+                entries[1],
+                (
+                    Path::new("sub/a.txt").to_path_buf(),
+                    7,
+                    String::from("content")
+                )
+            );
+        }
+    }
+}
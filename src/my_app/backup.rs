@@ -0,0 +1,244 @@
+use anyhow::{Context, Result};
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const CONFLICT_SUFFIX: &str = ".conflict";
+
+/// How the previous version of an overwritten file is named.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Naming {
+    /// `name.~1~`, `name.~2~`, ... keeping every version
+    Numbered,
+    /// `name` followed by the suffix, replacing the previous backup
+    Suffix(String),
+}
+
+/// Options for keeping the previous versions of overwritten destination files.
+#[derive(Clone, Debug)]
+pub struct Backup {
+    pub naming: Naming,
+    /// Directory receiving the backups, in the same layout as the destination, instead of
+    /// keeping them next to the files. Relative paths are resolved from the destination.
+    pub dir: Option<PathBuf>,
+    /// Number of numbered versions to keep for each file
+    pub keep: Option<usize>,
+}
+
+impl Backup {
+    /// Move `dest_path`, a file of the destination directory `dest`, out of the way. Returns
+    /// where it was moved to, and the older versions removed to honour the retention limit.
+    pub fn save(
+        &self,
+        dest: &Path,
+        dest_path: &Path,
+        dry_run: bool,
+    ) -> Result<(PathBuf, Vec<PathBuf>)> {
+        let backup_path = self.backup_path(dest, dest_path)?;
+        if dry_run {
+            return Ok((backup_path, Vec::new()));
+        }
+
+        let dir = backup_path.parent().unwrap();
+        fs::create_dir_all(dir)
+            .with_context(|| format!("Cannot create directory \"{}\"", dir.display()))?;
+        // A backup directory may be on another file system
+        fs::rename(dest_path, &backup_path)
+            .or_else(|_| fs::copy(dest_path, &backup_path).and_then(|_| fs::remove_file(dest_path)))
+            .with_context(|| format!("Cannot back up \"{}\"", dest_path.display()))?;
+
+        let mut removed = Vec::new();
+        if let (Naming::Numbered, Some(keep)) = (&self.naming, self.keep) {
+            let versions =
+                numbered_versions(&backup_path.with_file_name(dest_path.file_name().unwrap()))?;
+            for (_, path) in versions.iter().take(versions.len().saturating_sub(keep)) {
+                fs::remove_file(path)
+                    .with_context(|| format!("Cannot delete old backup \"{}\"", path.display()))?;
+                removed.push(path.clone());
+            }
+        }
+        Ok((backup_path, removed))
+    }
+
+    /// Whether `path`, a path of the destination directory `dest`, is a backup that mirroring
+    /// must leave alone. Outside of a backup directory, only names made by `save` from a file
+    /// that still exists in the source directory `src` count, so that e.g. a stale `notes~`
+    /// is not kept just because the suffix is `~`.
+    pub fn is_backup(&self, src: &Path, dest: &Path, path: &Path) -> bool {
+        if let Some(dir) = &self.dir {
+            return path.starts_with(dest.join(dir));
+        }
+        let name = match path.file_name().and_then(OsStr::to_str) {
+            Some(name) => name,
+            _ => return false,
+        };
+        let original = match &self.naming {
+            Naming::Numbered => version_of(name).and_then(|_| Some(&name[..name.rfind(".~")?])),
+            Naming::Suffix(suffix) => name.strip_suffix(suffix.as_str()),
+        };
+        match (original, path.strip_prefix(dest)) {
+            (Some(original), Ok(relative)) if !original.is_empty() => {
+                src.join(relative).with_file_name(original).is_file()
+            }
+            _ => false,
+        }
+    }
+
+    fn backup_path(&self, dest: &Path, dest_path: &Path) -> Result<PathBuf> {
+        let path = match &self.dir {
+            Some(dir) => dest.join(dir).join(dest_path.strip_prefix(dest)?),
+            _ => dest_path.to_path_buf(),
+        };
+        let mut name = path.file_name().unwrap().to_os_string();
+        match &self.naming {
+            Naming::Numbered => {
+                let last = numbered_versions(&path)?
+                    .last()
+                    .map_or(0, |(version, _)| *version);
+                name.push(format!(".~{}~", last + 1));
+            }
+            Naming::Suffix(suffix) => name.push(suffix),
+        }
+        Ok(path.with_file_name(name))
+    }
+}
+
+/// Free path next to `path` to move a conflicting destination file to before it is replaced:
+/// `name.conflict`, or `name.conflict.2`, `name.conflict.3`, ... if taken.
+pub fn conflict_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap().to_os_string();
+    name.push(CONFLICT_SUFFIX);
+    let mut candidate = path.with_file_name(&name);
+    let mut version = 1;
+    while candidate.symlink_metadata().is_ok() {
+        version += 1;
+        let mut numbered = name.clone();
+        numbered.push(format!(".{}", version));
+        candidate = path.with_file_name(numbered);
+    }
+    candidate
+}
+
+/// Whether a path was named by [`conflict_path`].
+pub fn is_conflict_copy(path: &Path) -> bool {
+    let name = match path.file_name().and_then(OsStr::to_str) {
+        Some(name) => name,
+        _ => return false,
+    };
+    let name = match name.rsplit_once('.') {
+        Some((rest, version)) if version.parse::<u64>().is_ok() => rest,
+        _ => name,
+    };
+    name.len() > CONFLICT_SUFFIX.len() && name.ends_with(CONFLICT_SUFFIX)
+}
+
+/// Existing numbered backups of `path`, from the oldest to the newest.
+fn numbered_versions(path: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    let dir = path.parent().unwrap();
+    let name = path.file_name().unwrap().to_string_lossy();
+    let mut versions = Vec::new();
+    if dir.is_dir() {
+        for entry in dir.read_dir()? {
+            let entry_name = entry?.file_name();
+            let version = entry_name
+                .to_str()
+                .and_then(|entry_name| entry_name.strip_prefix(name.as_ref()))
+                .and_then(version_of_suffix);
+            if let Some(version) = version {
+                versions.push((version, dir.join(&entry_name)));
+            }
+        }
+    }
+    versions.sort();
+    Ok(versions)
+}
+
+/// Version of a numbered backup from its file name, e.g. 3 for `main.rs.~3~`.
+fn version_of(name: &str) -> Option<u64> {
+    let start = name.rfind(".~")?;
+    if start == 0 {
+        return None;
+    }
+    version_of_suffix(&name[start..])
+}
+
+fn version_of_suffix(suffix: &str) -> Option<u64> {
+    suffix.strip_prefix(".~")?.strip_suffix('~')?.parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{conflict_path, is_conflict_copy, version_of, Backup, Naming};
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn versions_are_read_from_names() {
+        assert_eq!(version_of("main.rs.~3~"), Some(3));
+        assert_eq!(version_of("main.rs.~12~"), Some(12));
+        assert_eq!(version_of("main.rs"), None);
+        assert_eq!(version_of("main.rs.~x~"), None);
+        assert_eq!(version_of(".~1~"), None);
+    }
+
+    #[test]
+    fn numbered_backups_are_limited() {
+        let temp = tempfile::tempdir().unwrap();
+        let (src, dest) = (&temp.path().join("src"), &temp.path().join("dest"));
+        fs::create_dir(src).unwrap();
+        fs::create_dir(dest).unwrap();
+        fs::write(src.join("a.txt"), "").unwrap();
+        let file = dest.join("a.txt");
+        let backup = Backup {
+            naming: Naming::Numbered,
+            dir: None,
+            keep: Some(2),
+        };
+
+        for version in 1..=3 {
+            fs::write(&file, version.to_string()).unwrap();
+            let (path, _) = backup.save(dest, &file, false).unwrap();
+            assert_eq!(path, dest.join(format!("a.txt.~{}~", version)));
+            assert!(backup.is_backup(src, dest, &path));
+        }
+        assert!(!file.exists());
+        assert!(!dest.join("a.txt.~1~").exists());
+        assert_eq!(fs::read_to_string(dest.join("a.txt.~3~")).unwrap(), "3");
+        assert!(!backup.is_backup(src, dest, &dest.join("a.txt")));
+        // Only backups of files of the source are kept
+        fs::remove_file(src.join("a.txt")).unwrap();
+        assert!(!backup.is_backup(src, dest, &dest.join("a.txt.~3~")));
+    }
+
+    #[test]
+    fn only_backups_of_source_files_match_a_suffix() {
+        let temp = tempfile::tempdir().unwrap();
+        let (src, dest) = (&temp.path().join("src"), &temp.path().join("dest"));
+        fs::create_dir(src).unwrap();
+        fs::write(src.join("a.txt"), "").unwrap();
+        let backup = Backup {
+            naming: Naming::Suffix("~".to_string()),
+            dir: None,
+            keep: None,
+        };
+
+        assert!(backup.is_backup(src, dest, &dest.join("a.txt~")));
+        assert!(!backup.is_backup(src, dest, &dest.join("notes~")));
+        assert!(!backup.is_backup(src, dest, &dest.join("~")));
+    }
+
+    #[test]
+    fn conflict_copies_get_free_names() {
+        let temp = tempfile::tempdir().unwrap();
+        let dest = temp.path();
+        let file = dest.join("a.txt");
+
+        assert_eq!(conflict_path(&file), dest.join("a.txt.conflict"));
+        fs::write(dest.join("a.txt.conflict"), "").unwrap();
+        assert_eq!(conflict_path(&file), dest.join("a.txt.conflict.2"));
+        assert!(is_conflict_copy(&dest.join("a.txt.conflict")));
+        assert!(is_conflict_copy(&dest.join("a.txt.conflict.2")));
+        assert!(!is_conflict_copy(&dest.join("a.txt")));
+        assert!(!is_conflict_copy(Path::new(".conflict")));
+    }
+}
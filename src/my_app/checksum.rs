@@ -0,0 +1,12 @@
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+pub type Checksum = blake3::Hash;
+
+/// Compute the BLAKE3 hash of a file's content.
+pub fn file_hash<P: AsRef<Path>>(path: P) -> io::Result<Checksum> {
+    let mut hasher = blake3::Hasher::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize())
+}
@@ -0,0 +1,67 @@
+use super::app_options::CheckOptions;
+use super::ignore_file::{DirRules, IgnoreFile, Verdict};
+use anyhow::{Context, Result};
+use std::path::{Component, Path, PathBuf};
+
+/// Print, for each path given to `slimcopy check`, the rule deciding whether it is copied.
+pub fn check(options: &CheckOptions) -> Result<()> {
+    let ignore_file = IgnoreFile::load(&options.src, &options.rules)?;
+    for path in options.paths.iter() {
+        let absolute = absolute_path(path)?;
+        let relative = absolute.strip_prefix(&options.src).ok().with_context(|| {
+            format!(
+                "\"{}\" is not inside \"{}\"",
+                path.display(),
+                options.src.display()
+            )
+        })?;
+        let components: Vec<Component> = relative.components().collect();
+        let verdict = if components.is_empty() {
+            String::from("source directory, always copied")
+        } else {
+            explain(&ignore_file, &DirRules::empty(), &options.src, &components)?
+        };
+        println!("{}: {}", path.display(), verdict);
+    }
+    Ok(())
+}
+
+/// Walk down from `dir` towards the path made of `rest`, loading the rule files of each
+/// directory on the way, as the copy would. Stops early if a parent directory is ignored.
+fn explain(
+    ignore_file: &IgnoreFile,
+    parent_rules: &DirRules,
+    dir: &Path,
+    rest: &[Component],
+) -> Result<String> {
+    let rules = ignore_file.dir_rules(dir, parent_rules)?;
+    let path = dir.join(rest[0]);
+    let is_last = rest.len() == 1;
+    let is_dir = !is_last || path.is_dir();
+
+    Ok(match ignore_file.verdict(&rules, &path, is_dir) {
+        Verdict::Ignored(rule) if is_last => format!("ignored by {}", rule),
+        Verdict::Ignored(rule) => format!(
+            "ignored because \"{}\" is ignored by {}",
+            path.display(),
+            rule
+        ),
+        Verdict::NotIncluded if is_last => String::from("not matched by the include file"),
+        Verdict::NotIncluded => format!(
+            "ignored because \"{}\" is not matched by the include file",
+            path.display()
+        ),
+        Verdict::Copied(_) if !is_last => explain(ignore_file, &rules, &path, &rest[1..])?,
+        Verdict::Copied(Some(rule)) if rule.negation => format!("copied, re-included by {}", rule),
+        Verdict::Copied(Some(rule)) => format!("copied, included by {}", rule),
+        Verdict::Copied(None) => String::from("copied, no rule matched"),
+    })
+}
+
+/// Make a path absolute without requiring it to exist.
+fn absolute_path(path: &Path) -> Result<PathBuf> {
+    match path.canonicalize() {
+        Ok(path) => Ok(path),
+        Err(_) => Ok(std::env::current_dir()?.join(path)),
+    }
+}
@@ -0,0 +1,219 @@
+use super::app_options::SymlinkPolicy;
+use super::archive::{self, ArchiveFormat, Entry, EntryKind};
+use super::ignore_file::{DirRules, Rule, Verdict};
+use super::report::Action;
+use super::type_counter::TypeCounter;
+use super::{is_symlink, metadata, symlink, temp_file, written_action, Comparison, MyApp};
+use anyhow::{bail, Context, Result};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+/// A directory extracted from the archive, whose attributes are applied once its entries are
+/// written.
+struct ExtractedDir {
+    path: PathBuf,
+    mtime: Option<SystemTime>,
+    mode: Option<u32>,
+}
+
+impl MyApp {
+    /// Extract the entries of the source archive that the rules do not ignore into the
+    /// destination, as they are read. Entries are compared with their existing copies the same
+    /// way files of a source directory are, from the size and time recorded in the archive.
+    /// Paths are reported as if the archive were a directory.
+    pub(super) fn extract_archive(&self, format: ArchiveFormat) -> Result<TypeCounter> {
+        let mut counters = Vec::new();
+        let mut dirs = Vec::new();
+        archive::read_entries(&self.options.src, format, |entry| {
+            let path = self.options.src.join(&entry.path);
+            let result = self.extract_entry(entry, &mut dirs);
+            counters.push(self.recover(&path, result)?);
+            Ok(())
+        })?;
+
+        // Deepest directories first, as their timestamps change when their entries are written
+        if self.options.preserve.any() && !self.options.dry_run {
+            for dir in dirs.iter().rev() {
+                metadata::apply_entry(&dir.path, dir.mtime, dir.mode, &self.options.preserve)
+                    .with_context(|| {
+                        format!("Cannot preserve attributes of \"{}\"", dir.path.display())
+                    })?;
+            }
+        }
+        Ok(counters.iter().sum())
+    }
+
+    fn extract_entry(&self, entry: Entry, dirs: &mut Vec<ExtractedDir>) -> Result<TypeCounter> {
+        // The entry of the archive root, e.g. `./` in archives made with `tar -C dir .`
+        if entry.path.components().all(|c| c == Component::CurDir) {
+            return Ok(TypeCounter::new());
+        }
+        let relative = match archive::safe_path(&entry.path) {
+            Some(relative) => relative,
+            _ => {
+                self.log.add(&format!(
+                    "Skip entry outside of the destination \"{}\"",
+                    entry.path.display()
+                ));
+                return Ok(TypeCounter::new().count_skipped(1, entry.size));
+            }
+        };
+        let path = self.options.src.join(&relative);
+        let is_dir = matches!(entry.kind, EntryKind::Dir);
+
+        let components: Vec<Component> = relative.components().collect();
+        let (copied, rule) =
+            self.entry_verdict(&DirRules::empty(), &self.options.src, &components, is_dir)?;
+        if !copied {
+            let file_count = if is_dir { 0 } else { 1 };
+            self.log_skip(&path, file_count, rule.as_ref());
+            self.record(&path, Action::Skipped, entry.size, rule.as_ref());
+            return Ok(TypeCounter::new().count_skipped(file_count, entry.size));
+        }
+
+        self.check_inside_dest(&relative)?;
+        let dest_path = self.options.dest.join(&relative);
+        let (action, size) = match entry.kind {
+            EntryKind::Dir => {
+                if !dest_path.is_dir() && !self.options.dry_run {
+                    fs::create_dir_all(&dest_path).with_context(|| {
+                        format!("Cannot create directory \"{}\"", dest_path.display())
+                    })?;
+                }
+                dirs.push(ExtractedDir {
+                    path: dest_path,
+                    mtime: entry.mtime,
+                    mode: entry.mode,
+                });
+                return Ok(TypeCounter::new());
+            }
+            EntryKind::File => self.extract_file(&path, &dest_path, entry)?,
+            EntryKind::Symlink(target) => match self.options.symlinks {
+                SymlinkPolicy::CopyLink => self.write_symlink(&path, &target)?,
+                // The archive has no location of its own, so absolute links pointing inside the
+                // destination are the ones made relative
+                SymlinkPolicy::Relative => {
+                    let target = symlink::relative_target(&self.options.dest, &dest_path, &target)
+                        .unwrap_or(target);
+                    self.write_symlink(&path, &target)?
+                }
+                // A link inside an archive cannot be followed
+                SymlinkPolicy::Skip | SymlinkPolicy::Follow => {
+                    self.log
+                        .add(&format!("Skip symbolic link \"{}\"", path.display()));
+                    (Action::Symlink, 0)
+                }
+            },
+            EntryKind::Other => {
+                self.log
+                    .add(&format!("Skip special entry \"{}\"", path.display()));
+                (Action::Skipped, entry.size)
+            }
+        };
+        self.record(&path, action, size, rule.as_ref());
+        Ok(TypeCounter::new().count(action, size))
+    }
+
+    /// Decide whether the entry at `rest` below `dir` is extracted, checking its parent
+    /// directories first as a traversal of the tree would. Returns the deciding rule.
+    fn entry_verdict(
+        &self,
+        parent_rules: &DirRules,
+        dir: &Path,
+        rest: &[Component],
+        is_dir: bool,
+    ) -> Result<(bool, Option<Rule>)> {
+        let rules = self.ignore_file.dir_rules(dir, parent_rules)?;
+        let path = dir.join(rest[0]);
+        let is_last = rest.len() == 1;
+
+        Ok(
+            match self.ignore_file.verdict(&rules, &path, is_dir || !is_last) {
+                Verdict::Copied(_) if !is_last => {
+                    return self.entry_verdict(&rules, &path, &rest[1..], is_dir)
+                }
+                Verdict::Copied(rule) => (true, rule.cloned()),
+                Verdict::Ignored(rule) => (false, Some(rule.clone())),
+                Verdict::NotIncluded => (false, None),
+            },
+        )
+    }
+
+    /// Fail if a directory on the way to `relative` in the destination is a symbolic link, which
+    /// an archive could otherwise use to write outside of the destination.
+    fn check_inside_dest(&self, relative: &Path) -> Result<()> {
+        let mut dir = self.options.dest.clone();
+        for component in relative.parent().into_iter().flat_map(Path::components) {
+            dir.push(component);
+            if is_symlink(&dir) {
+                bail!(
+                    "Cannot extract through the symbolic link \"{}\"",
+                    dir.display()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn extract_file(&self, path: &Path, dest_path: &Path, entry: Entry) -> Result<(Action, u64)> {
+        let mut conflict = false;
+        match dest_path.symlink_metadata() {
+            Ok(dest_meta) if dest_meta.is_dir() => {
+                bail!(
+                    "Cannot replace directory \"{}\" with a file",
+                    dest_path.display()
+                );
+            }
+            // Replace the link itself rather than what it points to
+            Ok(dest_meta) if dest_meta.file_type().is_symlink() => {
+                if !self.options.dry_run {
+                    fs::remove_file(dest_path)?;
+                }
+            }
+            Ok(dest_meta) => {
+                // If force-copy is not set, extract only changed files
+                if !self.options.force_copy {
+                    match self.compare_stat(entry.size, entry.mtime, &dest_meta) {
+                        Comparison::Changed => (),
+                        Comparison::Newer => {
+                            if let Some(kept) =
+                                self.resolve_conflict(path, dest_path, entry.size)?
+                            {
+                                return Ok(kept);
+                            }
+                            conflict = true;
+                        }
+                        comparison => return Ok(self.unchanged(path, entry.size, comparison)),
+                    }
+                }
+                self.prepare_overwrite(dest_path, &dest_meta, conflict)?;
+            }
+            Err(_) => self.create_parent_dir(dest_path)?,
+        }
+
+        let action = written_action(conflict);
+        self.log.add(&format!("Extract {}", path.display()));
+        if self.options.dry_run {
+            return Ok((action, entry.size));
+        }
+        let size = temp_file::replace_with(dest_path, |temp_path| {
+            self.write_entry(entry, temp_path, dest_path)
+        })?;
+        Ok((action, size))
+    }
+
+    /// Write the content of an entry to `temp_path`, which then replaces `dest_path`, with the
+    /// preserved attributes.
+    fn write_entry(&self, entry: Entry, temp_path: &Path, dest_path: &Path) -> Result<u64> {
+        let size = File::create(temp_path)
+            .and_then(|mut file| io::copy(entry.reader, &mut file))
+            .with_context(|| format!("Failed to extract file to \"{}\"", dest_path.display()))?;
+        metadata::apply_entry(temp_path, entry.mtime, entry.mode, &self.options.preserve)
+            .with_context(|| {
+                format!("Cannot preserve attributes of \"{}\"", dest_path.display())
+            })?;
+        Ok(size)
+    }
+}
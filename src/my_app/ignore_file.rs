@@ -1,39 +1,328 @@
 // source from https://github.com/nathankleyn/gitignore.rs/commit/5e734ff842c9abde34137245e18ab51084fdc6b9
 mod ruleset;
 
-use anyhow::Result;
+use super::app_options::RuleOptions;
+use anyhow::{bail, Context, Result};
+pub(crate) use ruleset::Rule;
+pub use ruleset::RuleLine;
 use ruleset::*;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Name of the rule files Slimcopy looks for in the source tree.
+pub const RULES_FILE_NAME: &str = ".slimcopy_rules";
+
+/// Rule files honoured with `--use-gitignore`, from the lowest to the highest precedence.
+const GITIGNORE_FILE_NAMES: [&str; 3] = [".gitignore", ".ignore", RULES_FILE_NAME];
+
+/// Location of the user's rules applied to every copy, like git's `core.excludesFile`:
+/// `$XDG_CONFIG_HOME/slimcopy/rules`, or `~/.config/slimcopy/rules` (`%APPDATA%\slimcopy\rules`
+/// on Windows). Returns `None` if no such file exists.
+pub fn global_rules_file() -> Option<PathBuf> {
+    let config_dir = if cfg!(windows) {
+        std::env::var_os("APPDATA").map(PathBuf::from)
+    } else {
+        std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+    }?;
+    let path = config_dir.join("slimcopy").join("rules");
+    if path.is_file() {
+        Some(path)
+    } else {
+        None
+    }
+}
 
 pub struct IgnoreFile {
+    root: PathBuf,
+    paths: Vec<PathBuf>,
     ruleset: RuleSet,
+    /// Patterns given on the command line, which take precedence over every rule file
+    inline: Option<RuleSet>,
+    dir_rule_files: Vec<&'static str>,
+    /// If set, only paths matching these rules are copied.
+    allowlist: Option<RuleSet>,
+}
+
+/// The decision on whether a path is copied, with the rule it is based on.
+pub enum Verdict<'r> {
+    /// Excluded by an ignore rule
+    Ignored(&'r Rule),
+    /// Not matched by any rule of the include file
+    NotIncluded,
+    /// Copied (or traversed, for directories), either re-included by a negated ignore rule or
+    /// included by a rule of the include file
+    Copied(Option<&'r Rule>),
 }
 
 /// Given a single specific gitignore style file, allow matching against
 /// the rules within that file.
 impl IgnoreFile {
+    #[cfg(test)] // the application goes through `load` to add inline patterns
     pub fn new<P: AsRef<Path>, P2: AsRef<Path>>(root: P, path: P2) -> Result<IgnoreFile> {
-        let file = File::open(path)?;
-        let lines: Vec<String> = BufReader::new(file)
-            .lines()
-            .flat_map(|line| line.ok())
+        let lines = read_lines(path.as_ref())?;
+        IgnoreFile::from_lines(root, vec![path.as_ref().to_path_buf()], lines)
+    }
+
+    fn from_lines<P: AsRef<Path>>(
+        root: P,
+        paths: Vec<PathBuf>,
+        lines: Vec<RuleLine>,
+    ) -> Result<IgnoreFile> {
+        let rule_set = RuleSet::from_lines(&root, lines.as_slice())?;
+
+        Ok(IgnoreFile {
+            root: root.as_ref().to_path_buf(),
+            paths,
+            ruleset: rule_set,
+            inline: None,
+            dir_rule_files: vec![RULES_FILE_NAME],
+            allowlist: None,
+        })
+    }
+
+    /// Load the rules selected on the command line for the source directory `src`. The global
+    /// rules come first, then the `.gitignore` and `.ignore` files of `src` with
+    /// `--use-gitignore`, then the rule files in the given order, so that later rules take
+    /// precedence. Inline patterns override all of them, including the rule files found in the
+    /// tree.
+    pub fn load(src: &Path, options: &RuleOptions) -> Result<IgnoreFile> {
+        let gitignore_files: Vec<PathBuf> = if options.use_gitignore {
+            GITIGNORE_FILE_NAMES
+                .iter()
+                .filter(|name| **name != RULES_FILE_NAME)
+                .map(|name| src.join(name))
+                .filter(|path| path.is_file())
+                .collect()
+        } else {
+            Vec::new()
+        };
+        // The rules file of the source may have been created or deleted since it was looked for
+        let ignore_files: Vec<PathBuf> = if options.default_rules_file {
+            Some(src.join(RULES_FILE_NAME))
+                .filter(|path| path.is_file())
+                .into_iter()
+                .collect()
+        } else {
+            options.ignore_files.clone()
+        };
+        let paths: Vec<PathBuf> = options
+            .global_rules
+            .iter()
+            .chain(gitignore_files.iter())
+            .chain(ignore_files.iter())
+            .cloned()
             .collect();
-        let rule_set = RuleSet::new(root, lines.as_slice())?;
+        let mut lines = Vec::new();
+        for path in paths.iter() {
+            lines.extend(read_lines(path)?);
+        }
+        let mut ignore_file =
+            IgnoreFile::from_lines(src, paths, lines).context("Ignore file syntax error.")?;
+        if !options.patterns.is_empty() {
+            let lines: Vec<RuleLine> = options
+                .patterns
+                .iter()
+                .enumerate()
+                .map(|(idx, text)| RuleLine {
+                    text: text.clone(),
+                    source: None,
+                    line_number: idx + 1,
+                })
+                .collect();
+            ignore_file.inline = Some(
+                RuleSet::from_lines(src, lines.as_slice()).context("Invalid inline pattern.")?,
+            );
+        }
+        if options.use_gitignore {
+            ignore_file.use_gitignore();
+        }
+        if let Some(include_file) = &options.include_file {
+            let lines = read_lines(include_file)?;
+            ignore_file.allowlist = Some(
+                RuleSet::from_lines(src, lines.as_slice()).context("Include file syntax error.")?,
+            );
+        }
+        Ok(ignore_file)
+    }
 
-        Ok(IgnoreFile { ruleset: rule_set })
+    /// Also read `.gitignore` and `.ignore` files found in the source tree.
+    pub fn use_gitignore(&mut self) {
+        self.dir_rule_files = GITIGNORE_FILE_NAMES.to_vec();
     }
 
-    pub fn is_ignored<P: AsRef<Path>>(&self, path: P, is_dir: bool) -> bool {
-        self.ruleset.is_ignored(path, is_dir)
+    /// Whether `path` is one of the files the rules are read from, or may be read from if it
+    /// is created.
+    pub fn is_rule_file(&self, path: &Path) -> bool {
+        self.paths.iter().any(|own| own == path)
+            || path
+                .file_name()
+                .is_some_and(|name| self.dir_rule_files.iter().any(|file| name == *file))
+    }
+
+    /// Check a path against the rules of the directories containing it, falling back to the
+    /// rules of this file when none of them match.
+    pub fn is_ignored_in<P: AsRef<Path>>(&self, rules: &DirRules, path: P, is_dir: bool) -> bool {
+        !matches!(self.verdict(rules, path, is_dir), Verdict::Copied(_))
+    }
+
+    /// Decide whether a path is copied. Ignore rules are checked first; paths they do not
+    /// exclude must then be included by the include file, if there is one. Directories that are
+    /// not included themselves are still traversed when included paths may be found inside.
+    pub fn verdict<'r, P: AsRef<Path>>(
+        &'r self,
+        rules: &'r DirRules,
+        path: P,
+        is_dir: bool,
+    ) -> Verdict<'r> {
+        let path = path.as_ref();
+        let rule = self
+            .inline
+            .as_ref()
+            .and_then(|inline| inline.matching_rule(path, is_dir))
+            .or_else(|| rules.matching_rule(path, is_dir))
+            .or_else(|| self.ruleset.matching_rule(path, is_dir));
+        if let Some(rule) = rule.filter(|rule| !rule.negation) {
+            return Verdict::Ignored(rule);
+        }
+
+        let allowlist = match &self.allowlist {
+            Some(allowlist) => allowlist,
+            _ => return Verdict::Copied(rule),
+        };
+        match allowlist.matching_rule(path, is_dir) {
+            Some(include) if !include.negation => Verdict::Copied(Some(include)),
+            Some(_) => Verdict::NotIncluded,
+            None if rules.included => Verdict::Copied(rule),
+            None if is_dir && allowlist.may_match_below(path) => Verdict::Copied(rule),
+            None => Verdict::NotIncluded,
+        }
+    }
+
+    /// Read the rule files of `dir`, whose patterns are relative to `dir` and take precedence
+    /// over the ones of `parent`.
+    pub fn dir_rules<'a>(&self, dir: &Path, parent: &'a DirRules<'a>) -> Result<DirRules<'a>> {
+        let mut lines = Vec::new();
+        for name in self.dir_rule_files.iter() {
+            // The rule files of the source are already applied to the whole tree by `load`,
+            // below the rules file, which is either the default one or replaced by the files
+            // given with `-i`
+            if dir == self.root {
+                continue;
+            }
+            let path = dir.join(name);
+            // Files passed on the command line are already applied to the whole tree
+            if path.is_file() && !self.paths.iter().any(|own| same_file(&path, own)) {
+                lines.extend(read_lines(&path)?);
+            }
+        }
+
+        let ruleset = if lines.is_empty() {
+            None
+        } else {
+            Some(
+                RuleSet::from_lines(dir, lines.as_slice())
+                    .with_context(|| format!("Syntax error in rules of \"{}\"", dir.display()))?,
+            )
+        };
+        // Once a directory is included, so is everything inside it
+        let included = match self
+            .allowlist
+            .as_ref()
+            .and_then(|allowlist| allowlist.matching_rule(dir, true))
+        {
+            Some(rule) => !rule.negation,
+            None => parent.included,
+        };
+        Ok(DirRules {
+            parent: Some(parent),
+            ruleset,
+            included,
+        })
+    }
+}
+
+/// Rules read from the rule files of a directory, chained to the ones of its ancestors.
+pub struct DirRules<'a> {
+    parent: Option<&'a DirRules<'a>>,
+    ruleset: Option<RuleSet>,
+    /// Whether the directory is included by the include file
+    included: bool,
+}
+
+impl<'a> DirRules<'a> {
+    pub fn empty() -> Self {
+        DirRules {
+            parent: None,
+            ruleset: None,
+            included: false,
+        }
+    }
+
+    /// As in git, rules of deeper directories take precedence over the ones of their ancestors.
+    fn matching_rule(&self, path: &Path, is_dir: bool) -> Option<&Rule> {
+        self.ruleset
+            .as_ref()
+            .and_then(|ruleset| ruleset.matching_rule(path, is_dir))
+            .or_else(|| {
+                self.parent
+                    .and_then(|parent| parent.matching_rule(path, is_dir))
+            })
+    }
+}
+
+fn read_lines(path: &Path) -> Result<Vec<RuleLine>> {
+    read_lines_with_includes(path, &mut Vec::new())
+}
+
+/// Read the lines of a rule file, replacing include directives with the lines of the files they
+/// refer to. `including` holds the files currently being read, to detect include cycles.
+fn read_lines_with_includes(path: &Path, including: &mut Vec<PathBuf>) -> Result<Vec<RuleLine>> {
+    let file = File::open(path)?;
+    let canonical = path.canonicalize()?;
+    if including.contains(&canonical) {
+        bail!("Include cycle: \"{}\" includes itself", path.display());
+    }
+    including.push(canonical);
+
+    let source = Arc::new(path.to_path_buf());
+    let mut lines = Vec::new();
+    for (idx, text) in BufReader::new(file).lines().enumerate() {
+        let text = text
+            .with_context(|| format!("Cannot read line {} of \"{}\"", idx + 1, path.display()))?;
+        let line = RuleLine {
+            text,
+            source: Some(source.clone()),
+            line_number: idx + 1,
+        };
+        let context = || format!("Cannot include the file at {}:{}", path.display(), idx + 1);
+        match line.included_file().with_context(context)? {
+            Some(included) => {
+                lines.extend(read_lines_with_includes(&included, including).with_context(context)?)
+            }
+            _ => lines.push(line),
+        }
+    }
+
+    including.pop();
+    Ok(lines)
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::{IgnoreFile, RuleSet};
+    use super::{DirRules, IgnoreFile, RuleOptions, RuleSet};
     use std::path::PathBuf;
 
     macro_rules! ignore_file_from_test_repo {
@@ -51,6 +340,11 @@ mod test {
         RuleSet::new("foo", rules.iter()).unwrap()
     }
 
+    fn ruleset_from_root(root: &std::path::Path, raw_rules: &str) -> RuleSet {
+        let rules: Vec<String> = raw_rules.lines().map(|s| s.to_string()).collect();
+        RuleSet::new(root, rules.iter()).unwrap()
+    }
+
     #[test]
     #[should_panic]
     fn fails_when_file_is_missing() {
@@ -67,9 +361,253 @@ mod test {
     fn returns_correctly_an_ignorefile_from_valid_file() {
         let file = ignore_file_from_test_repo!(".gitignore");
 
+        // Rules read from a file also know where they come from, so only compare what they match
+        let summary = |rules: &[super::Rule]| -> Vec<_> {
+            rules
+                .iter()
+                .map(|rule| {
+                    (
+                        rule.pattern.clone(),
+                        rule.anchored,
+                        rule.dir_only,
+                        rule.negation,
+                    )
+                })
+                .collect()
+        };
         assert_eq!(
-            file.ruleset.rules,
-            ruleset_from_rules("*.no\nnot_me_either/\n/or_even_me").rules
+            summary(&file.ruleset.rules),
+            summary(&ruleset_from_rules("*.no\nnot_me_either/\n/or_even_me").rules)
         )
     }
+
+    fn nested_repo() -> PathBuf {
+        PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap())
+            .join("tests/resources/nested_repo")
+    }
+
+    #[test]
+    fn deeper_rule_files_take_precedence() {
+        let root = nested_repo();
+        let file = IgnoreFile::new(&root, root.join(".slimcopy_rules")).unwrap();
+        let top = DirRules::empty();
+        let root_rules = file.dir_rules(&root, &top).unwrap();
+        let sub_rules = file.dir_rules(&root.join("sub"), &root_rules).unwrap();
+
+        assert!(file.is_ignored_in(&root_rules, root.join("keep.log"), false));
+        assert!(file.is_ignored_in(&sub_rules, root.join("sub/other.log"), false));
+        assert!(!file.is_ignored_in(&sub_rules, root.join("sub/keep.log"), false));
+    }
+
+    #[test]
+    fn rules_are_relative_to_their_directory() {
+        let root = nested_repo();
+        let file = IgnoreFile::new(&root, root.join(".slimcopy_rules")).unwrap();
+        let top = DirRules::empty();
+        let root_rules = file.dir_rules(&root, &top).unwrap();
+        let sub_rules = file.dir_rules(&root.join("sub"), &root_rules).unwrap();
+
+        assert!(file.is_ignored_in(&sub_rules, root.join("sub/anchored"), false));
+        assert!(!file.is_ignored_in(&root_rules, root.join("anchored"), false));
+    }
+
+    #[test]
+    fn gitignore_files_are_opt_in() {
+        let root = nested_repo();
+        let mut file = IgnoreFile::new(&root, root.join(".slimcopy_rules")).unwrap();
+        let top = DirRules::empty();
+        let sub_rules = file.dir_rules(&root.join("sub"), &top).unwrap();
+        assert!(!file.is_ignored_in(&sub_rules, root.join("sub/a.tmp"), false));
+
+        file.use_gitignore();
+        let sub_rules = file.dir_rules(&root.join("sub"), &top).unwrap();
+        assert!(file.is_ignored_in(&sub_rules, root.join("sub/a.tmp"), false));
+    }
+
+    #[test]
+    fn rules_remember_their_origin() {
+        let file = ignore_file_from_test_repo!(".gitignore");
+        let rule = file.ruleset.matching_rule("not_me_either", true).unwrap();
+
+        assert_eq!(rule.line, "not_me_either/");
+        assert_eq!(rule.line_number, 2);
+        assert!(rule.source.as_ref().unwrap().ends_with(".gitignore"));
+        assert!(rule
+            .to_string()
+            .ends_with(":2: \"not_me_either/\" (directory only)"));
+    }
+
+    #[test]
+    fn inline_patterns_override_the_rules_file() {
+        let root = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap())
+            .join("tests/resources/fake_repo");
+        let options = RuleOptions {
+            ignore_files: vec![root.join(".gitignore")],
+            default_rules_file: false,
+            patterns: vec!["!keep.no".to_string(), "*.tmp".to_string()],
+            global_rules: None,
+            use_gitignore: false,
+            include_file: None,
+        };
+        let file = IgnoreFile::load(&root, &options).unwrap();
+        let top = DirRules::empty();
+
+        assert!(file.is_ignored_in(&top, root.join("other.no"), false));
+        assert!(!file.is_ignored_in(&top, root.join("keep.no"), false));
+        assert!(file.is_ignored_in(&top, root.join("a.tmp"), false));
+    }
+
+    #[test]
+    fn inline_patterns_override_nested_rule_files() {
+        let root = nested_repo();
+        let options = RuleOptions {
+            ignore_files: vec![root.join(".slimcopy_rules")],
+            default_rules_file: false,
+            patterns: vec!["*.log".to_string()],
+            global_rules: None,
+            use_gitignore: false,
+            include_file: None,
+        };
+        let file = IgnoreFile::load(&root, &options).unwrap();
+        let top = DirRules::empty();
+        let root_rules = file.dir_rules(&root, &top).unwrap();
+        let sub_rules = file.dir_rules(&root.join("sub"), &root_rules).unwrap();
+
+        // Re-included by sub/.slimcopy_rules, but excluded on the command line
+        assert!(file.is_ignored_in(&sub_rules, root.join("sub/keep.log"), false));
+    }
+
+    #[test]
+    fn given_rule_files_replace_the_one_of_the_source() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        std::fs::write(root.join(".slimcopy_rules"), "!*.txt\n*.log\n").unwrap();
+        std::fs::write(root.join("other_rules"), "*.txt\n").unwrap();
+        let options = RuleOptions {
+            ignore_files: vec![root.join("other_rules")],
+            default_rules_file: false,
+            patterns: Vec::new(),
+            global_rules: None,
+            use_gitignore: false,
+            include_file: None,
+        };
+        let file = IgnoreFile::load(root, &options).unwrap();
+        let top = DirRules::empty();
+        let root_rules = file.dir_rules(root, &top).unwrap();
+
+        assert!(file.is_ignored_in(&root_rules, root.join("c.txt"), false));
+        assert!(!file.is_ignored_in(&root_rules, root.join("a.log"), false));
+    }
+
+    #[test]
+    fn gitignore_files_of_the_source_come_before_its_rules_file() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        std::fs::write(root.join(".slimcopy_rules"), "!keep.log\n").unwrap();
+        std::fs::write(root.join(".gitignore"), "*.log\n").unwrap();
+        std::fs::write(root.join(".ignore"), "*.tmp\n").unwrap();
+        let options = RuleOptions {
+            ignore_files: vec![root.join(".slimcopy_rules")],
+            default_rules_file: false,
+            patterns: Vec::new(),
+            global_rules: None,
+            use_gitignore: true,
+            include_file: None,
+        };
+        let file = IgnoreFile::load(root, &options).unwrap();
+        let top = DirRules::empty();
+        let root_rules = file.dir_rules(root, &top).unwrap();
+
+        assert!(!file.is_ignored_in(&root_rules, root.join("keep.log"), false));
+        assert!(file.is_ignored_in(&root_rules, root.join("other.log"), false));
+        assert!(file.is_ignored_in(&root_rules, root.join("a.tmp"), false));
+    }
+
+    fn include_rules() -> PathBuf {
+        PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap())
+            .join("tests/resources/include_rules")
+    }
+
+    #[test]
+    fn included_files_are_expanded_in_place() {
+        let root = include_rules();
+        let file = IgnoreFile::new(&root, root.join("main_rules")).unwrap();
+        let top = DirRules::empty();
+
+        assert!(file.is_ignored_in(&top, root.join("other.tmp"), false));
+        assert!(!file.is_ignored_in(&top, root.join("keep.tmp"), false));
+        // Included from common/shared_rules, relative to that file
+        let rule = file.ruleset.matching_rule("a.bak", false).unwrap();
+        assert!(rule.source.as_ref().unwrap().ends_with("more_rules"));
+    }
+
+    #[test]
+    fn unreadable_lines_are_reported() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("rules");
+        std::fs::write(&path, b"*.tmp\n\xff\xfe\n*.log\n").unwrap();
+
+        let error = IgnoreFile::new(temp.path(), &path).err().unwrap();
+        assert!(format!("{:#}", error).contains("Cannot read line 2"));
+    }
+
+    #[test]
+    fn include_cycles_are_detected() {
+        let root = include_rules();
+        let error = IgnoreFile::new(&root, root.join("cycle_a")).err().unwrap();
+        assert!(format!("{:#}", error).contains("Include cycle"));
+    }
+
+    #[test]
+    fn later_rule_files_take_precedence() {
+        let root = include_rules();
+        let options = RuleOptions {
+            ignore_files: vec![root.join("main_rules"), root.join("override_rules")],
+            default_rules_file: false,
+            patterns: Vec::new(),
+            global_rules: None,
+            use_gitignore: false,
+            include_file: None,
+        };
+        let file = IgnoreFile::load(&root, &options).unwrap();
+        let top = DirRules::empty();
+
+        assert!(file.is_ignored_in(&top, root.join("other.tmp"), false));
+        assert!(!file.is_ignored_in(&top, root.join("a.bak"), false));
+    }
+
+    #[test]
+    fn global_rules_are_overridden_by_project_rules() {
+        let root = include_rules();
+        let options = RuleOptions {
+            ignore_files: vec![root.join("override_rules")],
+            default_rules_file: false,
+            global_rules: Some(root.join("common/shared_rules")),
+            patterns: Vec::new(),
+            use_gitignore: false,
+            include_file: None,
+        };
+        let file = IgnoreFile::load(&root, &options).unwrap();
+        let top = DirRules::empty();
+
+        assert!(file.is_ignored_in(&top, root.join("other.tmp"), false));
+        assert!(!file.is_ignored_in(&top, root.join("a.bak"), false));
+    }
+
+    #[test]
+    fn include_file_restricts_copied_paths() {
+        let root = nested_repo();
+        let mut file = IgnoreFile::new(&root, root.join(".slimcopy_rules")).unwrap();
+        file.allowlist = Some(ruleset_from_root(&root, "*.rs\n/docs/\n!/docs/drafts/"));
+        let top = DirRules::empty();
+        let root_rules = file.dir_rules(&root, &top).unwrap();
+        let docs_rules = file.dir_rules(&root.join("docs"), &root_rules).unwrap();
+
+        assert!(!file.is_ignored_in(&root_rules, root.join("main.rs"), false));
+        assert!(file.is_ignored_in(&root_rules, root.join("main.txt"), false));
+        // Traversed, since included files may be found inside
+        assert!(!file.is_ignored_in(&root_rules, root.join("src"), true));
+        assert!(!file.is_ignored_in(&docs_rules, root.join("docs/guide.txt"), false));
+        assert!(file.is_ignored_in(&docs_rules, root.join("docs/drafts"), true));
+    }
 }
@@ -1,7 +1,12 @@
 // source from https://github.com/nathankleyn/gitignore.rs/commit/5e734ff842c9abde34137245e18ab51084fdc6b9
-use anyhow::Result;
-use globset::{Candidate, GlobBuilder, GlobSet, GlobSetBuilder};
+use anyhow::{bail, Context, Result};
+use globset::{Candidate, GlobBuilder, GlobMatcher, GlobSet, GlobSetBuilder};
+use std::fmt::Display;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Directive replaced by the rules of another file, e.g. `%include ../common_rules`.
+const INCLUDE_DIRECTIVE: &str = "%include";
 
 /// Represents a set of rules that can be checked against to see if a path should be ignored within
 /// a Git repository.
@@ -13,35 +18,54 @@ pub struct RuleSet {
     root: PathBuf,
     pub(crate) rules: Vec<Rule>,
     tester: GlobSet,
+    /// For each rule, the globs matching each component of its pattern, or `None` if the
+    /// pattern may match at any depth.
+    components: Vec<Option<Vec<GlobMatcher>>>,
 }
 
 impl RuleSet {
     /// Construct a ruleset, given a path that is the root of the repository, and a set of rules,
     /// which is a vector
+    #[cfg(test)] // rule files are loaded with `from_lines` to keep track of their origin
     pub fn new<'a, P, I, S>(root: P, raw_rules: I) -> Result<RuleSet>
     where
         P: AsRef<Path>,
         I: IntoIterator<Item = &'a S>,
         S: AsRef<str> + 'a,
     {
+        let lines: Vec<RuleLine> = raw_rules
+            .into_iter()
+            .enumerate()
+            .map(|(idx, text)| RuleLine {
+                text: text.as_ref().to_string(),
+                source: None,
+                line_number: idx + 1,
+            })
+            .collect();
+        Self::from_lines(root, &lines)
+    }
+
+    /// Construct a ruleset from lines that remember where they were read from, so that matching
+    /// rules can be traced back to their origin.
+    pub fn from_lines<P: AsRef<Path>>(root: P, raw_rules: &[RuleLine]) -> Result<RuleSet> {
         // FIXME: Is there a better way without needing to hardcode a path here?
         let cleaned_root = Self::strip_prefix(root, Path::new("./"));
 
-        let lines = raw_rules
-            .into_iter()
-            .map(RuleSet::parse_line)
-            .collect::<Result<Vec<ParsedLine>>>()?;
-
-        let rules: Vec<Rule> = lines
-            .iter()
-            .filter_map(|parsed_line| {
-                match parsed_line {
-                    // FIXME: Remove this clone if possible, it's rank.
-                    &ParsedLine::WithRule(ref rule) => Some(rule.clone()),
-                    _ => None,
+        let mut rules = Vec::new();
+        for raw_rule in raw_rules.iter() {
+            match RuleSet::parse_line(&raw_rule.text)? {
+                ParsedLine::WithRule(mut rule) => {
+                    rule.line_number = raw_rule.line_number;
+                    rule.source = raw_rule.source.clone();
+                    rules.push(rule);
                 }
-            })
-            .collect();
+                // Includes are expanded when reading rule files
+                ParsedLine::Include(_) => {
+                    bail!("Unexpected include directive \"{}\"", raw_rule.text.trim())
+                }
+                _ => (),
+            }
+        }
 
         let mut tester_builder = GlobSetBuilder::new();
 
@@ -49,22 +73,73 @@ impl RuleSet {
         for rule in rules.iter() {
             let mut glob_builder = GlobBuilder::new(&rule.pattern);
             glob_builder.literal_separator(rule.anchored);
-            let glob = glob_builder.build()?;
+            let glob = glob_builder
+                .build()
+                .with_context(|| format!("Invalid rule {}", rule))?;
             tester_builder.add(glob);
         }
 
         let tester = tester_builder.build()?;
 
+        let components = rules
+            .iter()
+            .map(|rule| {
+                if rule.pattern.starts_with("**") {
+                    return Ok(None);
+                }
+                rule.pattern
+                    .split('/')
+                    .map(|part| Ok(GlobBuilder::new(part).build()?.compile_matcher()))
+                    .collect::<Result<Vec<_>>>()
+                    .map(Some)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
         Ok(RuleSet {
             root: cleaned_root,
             rules,
             tester,
+            components,
         })
     }
 
+    /// Check whether a non-negated rule could match something inside the given directory, so
+    /// that it has to be traversed even though the directory itself is not matched.
+    pub fn may_match_below<P: AsRef<Path>>(&self, dir: P) -> bool {
+        let cleaned_dir = Self::strip_prefix(dir.as_ref(), &self.root);
+        let dir_components: Vec<_> = cleaned_dir.iter().collect();
+
+        self.rules
+            .iter()
+            .zip(self.components.iter())
+            .filter(|(rule, _)| !rule.negation)
+            .any(|(_, components)| match components {
+                None => true,
+                Some(components) => {
+                    for (idx, dir_component) in dir_components.iter().enumerate() {
+                        match components.get(idx) {
+                            Some(glob) if glob.glob().glob() == "**" => return true,
+                            Some(glob) if glob.is_match(dir_component) => (),
+                            _ => return false,
+                        }
+                    }
+                    dir_components.len() < components.len()
+                }
+            })
+    }
+
     /// Check if the given path should be considered ignored as per the rules contained within
     /// the current ruleset.
+    #[cfg(test)] // the application goes through `matching_rule` to layer rulesets
     pub fn is_ignored<P: AsRef<Path>>(&self, path: P, is_dir: bool) -> bool {
+        self.matching_rule(path, is_dir)
+            .map(|rule| !rule.negation)
+            .unwrap_or(false)
+    }
+
+    /// Find the rule that decides whether the given path is ignored, which is the last one
+    /// matching it.
+    pub fn matching_rule<P: AsRef<Path>>(&self, path: P, is_dir: bool) -> Option<&Rule> {
         // FIXME: Is there a better way without needing to hardcode a path here?
         let mut cleaned_path = Self::strip_prefix(path.as_ref(), Path::new("./"));
         cleaned_path = Self::strip_prefix(cleaned_path.as_path(), &self.root);
@@ -72,7 +147,7 @@ impl RuleSet {
         let results = self.tester.matches_candidate(&candidate);
 
         for idx in results.iter().rev() {
-            let ref rule = self.rules[*idx];
+            let rule = &self.rules[*idx];
 
             // We must backtrack through the finds until we find one that is_dir
             // and rule.dir_only agree on.
@@ -80,10 +155,10 @@ impl RuleSet {
                 continue;
             }
 
-            return !rule.negation;
+            return Some(rule);
         }
 
-        false
+        None
     }
 
     /// Given a raw pattern, parse it and attempt to construct a rule out of it. The pattern pattern
@@ -101,6 +176,15 @@ impl RuleSet {
             return Ok(ParsedLine::Comment);
         }
 
+        // Other patterns starting with the directive, e.g. `%includes`, are plain patterns
+        match pattern.strip_prefix(INCLUDE_DIRECTIVE) {
+            Some("") => bail!("Missing path after {}", INCLUDE_DIRECTIVE),
+            Some(path) if path.starts_with(char::is_whitespace) => {
+                return Ok(ParsedLine::Include(PathBuf::from(path.trim())))
+            }
+            _ => (),
+        }
+
         let negation = pattern.starts_with('!');
         if negation {
             pattern = pattern.trim_start_matches('!').trim();
@@ -136,6 +220,9 @@ impl RuleSet {
             anchored,
             dir_only,
             negation,
+            line: raw_rule.as_ref().trim().to_string(),
+            line_number: 0,
+            source: None,
         }))
     }
 
@@ -161,18 +248,71 @@ pub(crate) struct Rule {
     /// Whether the rule should, if it matches, negate any previously matching
     /// patterns. This flag has no effect if no previous patterns had matched.
     pub negation: bool,
+    /// The line of the rule file this rule was parsed from.
+    pub line: String,
+    /// Position of that line in its file, starting from 1.
+    pub line_number: usize,
+    /// The rule file containing the line, if the rule was read from a file.
+    pub source: Option<Arc<PathBuf>>,
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.source {
+            Some(source) => write!(f, "{}:{}: ", source.display(), self.line_number)?,
+            None => write!(f, "command line: ")?,
+        }
+        write!(f, "\"{}\"", self.line)?;
+
+        let flags: Vec<&str> = [
+            (self.negation, "negation"),
+            (self.dir_only, "directory only"),
+        ]
+        .iter()
+        .filter_map(|&(set, name)| if set { Some(name) } else { None })
+        .collect();
+        if !flags.is_empty() {
+            write!(f, " ({})", flags.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// A raw line of a rule file, together with where it was read from.
+#[derive(Clone, Debug)]
+pub struct RuleLine {
+    pub text: String,
+    pub source: Option<Arc<PathBuf>>,
+    pub line_number: usize,
+}
+
+impl RuleLine {
+    /// If this line is an include directive, the file it refers to. Relative paths are resolved
+    /// from the directory of the file containing the line.
+    pub fn included_file(&self) -> Result<Option<PathBuf>> {
+        match RuleSet::parse_line(&self.text)? {
+            ParsedLine::Include(path) => Ok(Some(
+                match self.source.as_ref().and_then(|source| source.parent()) {
+                    Some(dir) => dir.join(path),
+                    _ => path,
+                },
+            )),
+            _ => Ok(None),
+        }
+    }
 }
 
 enum ParsedLine {
     Empty,
     Comment,
     WithRule(Rule),
+    Include(PathBuf),
 }
 
 #[cfg(test)]
 mod test {
-    use super::RuleSet;
-    use std::path::Path;
+    use super::{RuleLine, RuleSet};
+    use std::path::{Path, PathBuf};
 
     fn ruleset_from_rules<P: AsRef<Path>, S: AsRef<str>>(root: P, raw_rules: S) -> RuleSet {
         let rules: Vec<String> = raw_rules.as_ref().lines().map(|s| s.to_string()).collect();
@@ -204,7 +344,7 @@ mod test {
         };
     }
 
-    const ROOT: &'static str = "/home/test/some/repo";
+    const ROOT: &str = "/home/test/some/repo";
 
     ignored!(ig1, ROOT, "months", "months");
     ignored!(ig2, ROOT, "*.lock", "Cargo.lock");
@@ -236,6 +376,8 @@ mod test {
     ignored!(ig28, ROOT, "src/*.rs", "src/grep/src/main.rs");
     ignored!(ig29, "./src", "/llvm/", "./src/llvm", true);
     ignored!(ig30, ROOT, "node_modules/ ", "node_modules", true);
+    ignored!(ig31, ROOT, "%includes", "%includes");
+    ignored!(ig32, ROOT, "%include_dir/", "%include_dir", true);
 
     not_ignored!(ignot1, ROOT, "amonths", "months");
     not_ignored!(ignot2, ROOT, "monthsa", "months");
@@ -257,6 +399,42 @@ mod test {
         "./third_party/protobuf/csharp/src/packages/repositories.config"
     );
     not_ignored!(ignot15, ROOT, "!/bar", "foo/bar");
+
+    #[test]
+    fn include_directives_need_a_path() {
+        let line = |text: &str| RuleLine {
+            text: text.to_string(),
+            source: None,
+            line_number: 1,
+        };
+        assert_eq!(
+            line("%include  ../common ").included_file().unwrap(),
+            Some(PathBuf::from("../common"))
+        );
+        assert!(line("%include").included_file().is_err());
+        assert_eq!(line("%includes").included_file().unwrap(), None);
+    }
+
+    macro_rules! may_match_below {
+        ($name:ident, $rules:expr, $dir:expr, $expected:expr) => {
+            #[test]
+            fn $name() {
+                let rs = ruleset_from_rules(ROOT, $rules);
+                assert_eq!(rs.may_match_below($dir), $expected);
+            }
+        };
+    }
+
+    may_match_below!(below1, "*.rs", "src/deep", true);
+    may_match_below!(below2, "/docs/**", "docs", true);
+    may_match_below!(below3, "/docs/**", "docs/a/b", true);
+    may_match_below!(below4, "/docs/**", "src", false);
+    may_match_below!(below5, "/src/*.rs", "src", true);
+    may_match_below!(below6, "/src/*.rs", "src/deep", false);
+    may_match_below!(below7, "/Cargo.toml", "src", false);
+    may_match_below!(below8, "/a/*/c/d", "a/b/c", true);
+    may_match_below!(below9, "/a/*/c/d", "a/b/x", false);
+    may_match_below!(below10, "!*.rs", "src", false);
 }
 
 #[cfg(all(test, feature = "benchmarks"))]
@@ -265,7 +443,7 @@ mod benchmark {
     use std::path::Path;
     use test::Bencher;
 
-    const ROOT: &'static str = "/home/test/some/repo";
+    const ROOT: &str = "/home/test/some/repo";
 
     // FIXME: DRY this up, perhaps with a test utils module.
     fn ruleset_from_rules<P: AsRef<Path>, S: AsRef<str>>(root: P, raw_rules: S) -> RuleSet {
@@ -0,0 +1,80 @@
+use super::app_options::ListOptions;
+use super::ignore_file::{DirRules, IgnoreFile, Verdict};
+use anyhow::Result;
+use std::fs;
+use std::io::{self, stdout, BufWriter, ErrorKind, Write};
+use std::path::Path;
+
+/// Print the paths of the source that a copy would include, relative to the source, as
+/// `slimcopy list` does. Only files and symbolic links are listed, since tools given a
+/// directory take all of it. With `--ignored`, the ignored paths are listed instead; an
+/// ignored directory is listed once rather than with everything inside it.
+pub fn list(options: &ListOptions) -> Result<()> {
+    let ignore_file = IgnoreFile::load(&options.src, &options.rules)?;
+    let mut out = BufWriter::new(stdout().lock());
+    let result = walk(
+        &ignore_file,
+        &DirRules::empty(),
+        &options.src,
+        &mut |path, meta, verdict| {
+            let copied = matches!(verdict, Verdict::Copied(_));
+            if copied == options.ignored || (copied && meta.is_dir()) {
+                return Ok(());
+            }
+            write_path(&mut out, path.strip_prefix(&options.src)?)?;
+            out.write_all(if options.null { b"\0" } else { b"\n" })?;
+            Ok(())
+        },
+    )
+    .and_then(|_| Ok(out.flush()?));
+
+    // The reader may stop early, e.g. `head`
+    match result {
+        Err(e) if is_broken_pipe(&e) => Ok(()),
+        result => result,
+    }
+}
+
+fn is_broken_pipe(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<io::Error>()
+        .is_some_and(|e| e.kind() == ErrorKind::BrokenPipe)
+}
+
+/// Walk the directory `dir` in name order, calling `f` with the metadata of each entry and the
+/// verdict of the rules on it. Directories are entered only if copied, and symbolic links are
+/// not followed.
+pub fn walk<F>(
+    ignore_file: &IgnoreFile,
+    parent_rules: &DirRules,
+    dir: &Path,
+    f: &mut F,
+) -> Result<()>
+where
+    F: FnMut(&Path, &fs::Metadata, &Verdict) -> Result<()>,
+{
+    let rules = ignore_file.dir_rules(dir, parent_rules)?;
+    let mut entries = dir.read_dir()?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let meta = path.symlink_metadata()?;
+        let verdict = ignore_file.verdict(&rules, &path, meta.is_dir());
+        f(&path, &meta, &verdict)?;
+        if meta.is_dir() && matches!(verdict, Verdict::Copied(_)) {
+            walk(ignore_file, &rules, &path, f)?;
+        }
+    }
+    Ok(())
+}
+
+/// Write a path as is, so that names that are not valid UTF-8 survive the trip through a pipe.
+fn write_path<W: Write>(out: &mut W, path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+        out.write_all(path.as_os_str().as_bytes())
+    }
+    #[cfg(not(unix))]
+    out.write_all(path.to_string_lossy().as_bytes())
+}
@@ -1,11 +1,11 @@
-use std::cell::RefCell;
+use std::sync::Mutex;
 use std::{fs::File, io::Write, path::Path};
 
-// Logger is not thread-safe since RefCell is used.
+// Logger can be shared between threads; writes to the log file are serialized by a mutex.
 
 pub struct Logger {
     mute: bool,
-    file: Option<RefCell<File>>,
+    file: Option<Mutex<File>>,
 }
 
 impl Logger {
@@ -15,7 +15,7 @@ impl Logger {
 
     pub fn to_file<P: AsRef<Path>>(path: P) -> Self {
         let file = match File::create(path) {
-            Ok(f) => Some(RefCell::new(f)),
+            Ok(f) => Some(Mutex::new(f)),
             _ => None,
         };
         Logger { mute: false, file }
@@ -26,9 +26,9 @@ impl Logger {
             match &self.file {
                 None => println!("{}", msg),
                 Some(f) => {
-                    let mut file = f.borrow_mut();
-                    let _ = file.write(msg.as_bytes());
-                    let _ = file.write("\n".as_bytes());
+                    // Write the line in one go so lines from different threads do not interleave
+                    let mut file = f.lock().unwrap();
+                    let _ = file.write_all(format!("{}\n", msg).as_bytes());
                 }
             }
         }
@@ -0,0 +1,184 @@
+use super::temp_file;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{BufReader, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Name of the manifest kept in the destination directory.
+pub const MANIFEST_FILE_NAME: &str = ".slimcopy_manifest";
+
+/// State of a copied file when it was last copied or found up to date.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Entry {
+    /// Size of the source file, which is also the size of its copy
+    pub size: u64,
+    /// Modification time of the source file
+    pub mtime: SystemTime,
+    /// Modification time of the copy, to notice changes made in the destination
+    pub dest_mtime: SystemTime,
+    /// Checksum of the content, recorded when comparing by hash
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+impl Entry {
+    pub fn new(
+        src_meta: &fs::Metadata,
+        dest_meta: &fs::Metadata,
+        hash: Option<String>,
+    ) -> Option<Self> {
+        Some(Entry {
+            size: src_meta.len(),
+            mtime: src_meta.modified().ok()?,
+            dest_mtime: dest_meta.modified().ok()?,
+            hash,
+        })
+    }
+
+    /// Whether the source file looks unchanged since this entry was recorded.
+    pub fn matches_source(&self, src_meta: &fs::Metadata) -> bool {
+        self.size == src_meta.len() && src_meta.modified().ok() == Some(self.mtime)
+    }
+
+    /// Whether the copy in the destination is still the one this entry was recorded for.
+    pub fn matches_copy(&self, dest_meta: &fs::Metadata) -> bool {
+        self.size == dest_meta.len() && dest_meta.modified().ok() == Some(self.dest_mtime)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct ManifestFile {
+    /// Source directory the destination was copied from
+    source: PathBuf,
+    /// Entries by path relative to the source and destination directories
+    files: BTreeMap<PathBuf, Entry>,
+}
+
+/// Record of the files copied to a destination, used to tell which source files changed since
+/// the previous run without looking at their copies. The entries of the previous run are
+/// looked up, while the ones of the current run are collected to be saved at the end. Once
+/// saved, they are the ones looked up, so that watching can save after each batch of changes.
+pub struct Manifest {
+    path: PathBuf,
+    source: PathBuf,
+    previous: Mutex<HashMap<PathBuf, Entry>>,
+    current: Mutex<BTreeMap<PathBuf, Entry>>,
+}
+
+impl Manifest {
+    /// Read the manifest of `dest`, if any. A manifest recorded for another source directory is
+    /// not used.
+    pub fn load(src: &Path, dest: &Path) -> Result<Self> {
+        let path = dest.join(MANIFEST_FILE_NAME);
+        let previous = match File::open(&path) {
+            Ok(file) => {
+                let manifest: ManifestFile = serde_json::from_reader(BufReader::new(file))
+                    .with_context(|| {
+                        format!(
+                            "Invalid manifest \"{}\", delete it to start over",
+                            path.display()
+                        )
+                    })?;
+                if manifest.source == src {
+                    manifest.files.into_iter().collect()
+                } else {
+                    HashMap::new()
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e).with_context(|| format!("Cannot read \"{}\"", path.display())),
+        };
+
+        Ok(Manifest {
+            path,
+            source: src.to_path_buf(),
+            previous: Mutex::new(previous),
+            current: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    /// Entry recorded by the previous run for `path`, relative to the source directory.
+    pub fn get(&self, path: &Path) -> Option<Entry> {
+        self.previous.lock().unwrap().get(path).cloned()
+    }
+
+    pub fn insert(&self, path: &Path, entry: Entry) {
+        self.current
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), entry);
+    }
+
+    /// Write the entries of the current run, replacing the previous manifest. The current run
+    /// goes on collecting entries, so that a later save still holds the ones saved before.
+    pub fn save(&self) -> Result<()> {
+        let current = self.current.lock().unwrap();
+        temp_file::write_atomically(&self.path, |writer| {
+            Ok(serde_json::to_writer(
+                writer,
+                &ManifestFile {
+                    source: self.source.clone(),
+                    files: current.clone(),
+                },
+            )?)
+        })
+        .with_context(|| format!("Cannot write manifest \"{}\"", self.path.display()))?;
+        *self.previous.lock().unwrap() = current.clone().into_iter().collect();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Entry, Manifest};
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn entries_survive_a_round_trip() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let file = dir.join("a.txt");
+        fs::write(&file, "content").unwrap();
+        let meta = file.metadata().unwrap();
+
+        let manifest = Manifest::load(Path::new("/src"), dir).unwrap();
+        assert!(manifest.get(Path::new("a.txt")).is_none());
+        let entry = Entry::new(&meta, &meta, Some("abc".to_string())).unwrap();
+        manifest.insert(Path::new("a.txt"), entry.clone());
+        manifest.save().unwrap();
+
+        let manifest = Manifest::load(Path::new("/src"), dir).unwrap();
+        assert_eq!(manifest.get(Path::new("a.txt")), Some(entry.clone()));
+        assert!(entry.matches_source(&meta));
+        assert!(entry.matches_copy(&meta));
+
+        // Entries recorded for another source are not used
+        let manifest = Manifest::load(Path::new("/elsewhere"), dir).unwrap();
+        assert!(manifest.get(Path::new("a.txt")).is_none());
+    }
+
+    #[test]
+    fn saved_entries_are_looked_up_and_saved_again() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let file = dir.join("a.txt");
+        fs::write(&file, "content").unwrap();
+        let meta = file.metadata().unwrap();
+        let entry = Entry::new(&meta, &meta, None).unwrap();
+
+        let manifest = Manifest::load(Path::new("/src"), dir).unwrap();
+        manifest.insert(Path::new("a.txt"), entry.clone());
+        manifest.save().unwrap();
+        assert_eq!(manifest.get(Path::new("a.txt")), Some(entry.clone()));
+
+        // Saving again without new entries keeps the previous ones
+        manifest.save().unwrap();
+        let manifest = Manifest::load(Path::new("/src"), dir).unwrap();
+        assert_eq!(manifest.get(Path::new("a.txt")), Some(entry));
+    }
+}
@@ -0,0 +1,81 @@
+use filetime::FileTime;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Which metadata of the source should be carried over to copied files and directories.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Preserve {
+    pub times: bool,
+    pub mode: bool,
+    pub owner: bool,
+    pub xattr: bool,
+}
+
+impl Preserve {
+    pub fn any(&self) -> bool {
+        self.times || self.mode || self.owner || self.xattr
+    }
+}
+
+/// Copy the selected metadata from `src` to `dest`. Timestamps are applied last, since changing
+/// the other attributes may touch them.
+pub fn apply(src: &Path, dest: &Path, preserve: &Preserve) -> io::Result<()> {
+    let meta = src.metadata()?;
+
+    #[cfg(unix)]
+    if preserve.xattr {
+        for name in xattr::list(src)? {
+            if let Some(value) = xattr::get(src, &name)? {
+                xattr::set(dest, &name, &value)?;
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    if preserve.owner {
+        use std::os::unix::fs::MetadataExt;
+        match std::os::unix::fs::chown(dest, Some(meta.uid()), Some(meta.gid())) {
+            // Only privileged users may give files away, so this is best effort
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => (),
+            result => result?,
+        }
+    }
+
+    if preserve.mode {
+        fs::set_permissions(dest, meta.permissions())?;
+    }
+
+    if preserve.times {
+        filetime::set_file_times(
+            dest,
+            FileTime::from_last_access_time(&meta),
+            FileTime::from_last_modification_time(&meta),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Apply the selected metadata recorded in an archive entry to `dest`. Archives only carry the
+/// modification time and the permissions that slimcopy restores.
+pub fn apply_entry(
+    dest: &Path,
+    mtime: Option<SystemTime>,
+    mode: Option<u32>,
+    preserve: &Preserve,
+) -> io::Result<()> {
+    #[cfg(unix)]
+    if let (true, Some(mode)) = (preserve.mode, mode) {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(dest, fs::Permissions::from_mode(mode))?;
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+
+    if let (true, Some(mtime)) = (preserve.times, mtime) {
+        filetime::set_file_mtime(dest, FileTime::from_system_time(mtime))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,137 @@
+use super::type_counter::TypeCounter;
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// What happened to a single file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Copied,
+    Linked,
+    Skipped,
+    Old,
+    Identical,
+    /// Changed in the destination since it was copied: left alone, overwritten or renamed as
+    /// `--conflict` says
+    Conflict,
+    Symlink,
+    Deleted,
+    Mismatch,
+    Error,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportFormat {
+    Json,
+    Csv,
+}
+
+impl FromStr for ReportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(ReportFormat::Json),
+            "csv" => Ok(ReportFormat::Csv),
+            _ => bail!("Unknown report format \"{}\"", s),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Record {
+    path: PathBuf,
+    action: Action,
+    size: u64,
+    rule: Option<String>,
+}
+
+/// Collects a record for every file processed, to be written out as JSON or CSV at the end of
+/// the run.
+pub struct Report {
+    format: ReportFormat,
+    path: PathBuf,
+    records: Mutex<Vec<Record>>,
+}
+
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    totals: Option<&'a TypeCounter>,
+    files: &'a [Record],
+}
+
+/// A CSV row, which is either a file (`path` set) or the total of an action (`path` empty).
+#[derive(Serialize)]
+struct CsvRow<'a> {
+    path: &'a Path,
+    action: Action,
+    count: u64,
+    size: u64,
+    rule: Option<&'a str>,
+}
+
+impl Report {
+    pub fn new<P: AsRef<Path>>(format: ReportFormat, path: P) -> Self {
+        Report {
+            format,
+            path: path.as_ref().to_path_buf(),
+            records: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn add<P: AsRef<Path>>(&self, path: P, action: Action, size: u64, rule: Option<String>) {
+        self.records.lock().unwrap().push(Record {
+            path: path.as_ref().to_path_buf(),
+            action,
+            size,
+            rule,
+        });
+    }
+
+    /// Write the records collected so far, with the `totals` of the run. A failed run has no
+    /// totals, since they would only count the files processed before the failure.
+    pub fn write(&self, totals: Option<&TypeCounter>) -> Result<()> {
+        let mut records = self.records.lock().unwrap();
+        records.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let file = File::create(&self.path)
+            .with_context(|| format!("Cannot create report \"{}\"", self.path.display()))?;
+        match self.format {
+            ReportFormat::Json => serde_json::to_writer_pretty(
+                file,
+                &JsonReport {
+                    totals,
+                    files: &records,
+                },
+            )?,
+            ReportFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(file);
+                for record in records.iter() {
+                    writer.serialize(CsvRow {
+                        path: &record.path,
+                        action: record.action,
+                        count: 1,
+                        size: record.size,
+                        rule: record.rule.as_deref(),
+                    })?;
+                }
+                for (action, count, size) in totals.iter().flat_map(|totals| totals.by_action()) {
+                    writer.serialize(CsvRow {
+                        path: Path::new(""),
+                        action,
+                        count,
+                        size,
+                        rule: None,
+                    })?;
+                }
+                writer.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
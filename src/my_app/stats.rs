@@ -0,0 +1,110 @@
+use super::app_options::StatsOptions;
+use super::ignore_file::{DirRules, IgnoreFile, Verdict};
+use super::list::walk;
+use anyhow::Result;
+use size_format::SizeFormatterBinary;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Number of files and their total size.
+#[derive(Clone, Copy, Default)]
+struct Total {
+    count: u64,
+    size: u64,
+}
+
+impl Total {
+    fn add(&mut self, other: Total) {
+        self.count += other.count;
+        self.size += other.size;
+    }
+}
+
+/// Print how many files the rules copy and ignore, with their sizes, and what each rule
+/// ignores, as `slimcopy stats` does. Symbolic links are not counted.
+pub fn stats(options: &StatsOptions) -> Result<()> {
+    let ignore_file = IgnoreFile::load(&options.src, &options.rules)?;
+    let mut copied = Total::default();
+    let mut ignored = Total::default();
+    let mut by_rule: HashMap<String, Total> = HashMap::new();
+    walk(
+        &ignore_file,
+        &DirRules::empty(),
+        &options.src,
+        &mut |path, meta, verdict| {
+            let reason = match verdict {
+                Verdict::Copied(_) => {
+                    if meta.is_file() {
+                        copied.add(Total {
+                            count: 1,
+                            size: meta.len(),
+                        });
+                    }
+                    return Ok(());
+                }
+                Verdict::Ignored(rule) => rule.to_string(),
+                Verdict::NotIncluded => String::from("not included"),
+            };
+            let total = tree_total(path, meta)?;
+            ignored.add(total);
+            by_rule.entry(reason).or_default().add(total);
+            Ok(())
+        },
+    )?;
+
+    println!(
+        "{:>6} file(s) copied,  size = {:>8}B",
+        copied.count,
+        SizeFormatterBinary::new(copied.size)
+    );
+    println!(
+        "{:>6} file(s) ignored, size = {:>8}B",
+        ignored.count,
+        SizeFormatterBinary::new(ignored.size)
+    );
+    // The rules saving the most space first
+    let mut by_rule: Vec<(String, Total)> = by_rule.into_iter().collect();
+    by_rule.sort_by(|(a_rule, a), (b_rule, b)| b.size.cmp(&a.size).then(a_rule.cmp(b_rule)));
+    for (rule, total) in by_rule.iter() {
+        println!(
+            "{:>6} file(s),         size = {:>8}B, {}",
+            total.count,
+            SizeFormatterBinary::new(total.size),
+            rule
+        );
+    }
+    Ok(())
+}
+
+/// Files below `path`, or `path` itself if it is a file.
+fn tree_total(path: &Path, meta: &fs::Metadata) -> Result<Total> {
+    let mut total = Total::default();
+    if meta.is_dir() {
+        for entry in path.read_dir()? {
+            let path = entry?.path();
+            total.add(tree_total(&path, &path.symlink_metadata()?)?);
+        }
+    } else if meta.is_file() {
+        total.add(Total {
+            count: 1,
+            size: meta.len(),
+        });
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod test {
+    use super::tree_total;
+    use std::path::PathBuf;
+
+    #[test]
+    fn files_below_a_directory_are_counted() {
+        let path = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap())
+            .join("tests/resources/include_rules");
+        let total = tree_total(&path, &path.metadata().unwrap()).unwrap();
+        assert_eq!(total.count, 6);
+        assert!(total.size > 0);
+    }
+}
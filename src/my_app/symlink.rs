@@ -0,0 +1,94 @@
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// Create a symbolic link at `link` pointing to `target`. On Windows the kind of link depends on
+/// whether the target is a directory, so it is resolved relative to the link's location.
+pub fn create(target: &Path, link: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(target, link)
+    }
+    #[cfg(windows)]
+    {
+        let resolved = link
+            .parent()
+            .map_or(target.to_path_buf(), |p| p.join(target));
+        if resolved.is_dir() {
+            std::os::windows::fs::symlink_dir(target, link)
+        } else {
+            std::os::windows::fs::symlink_file(target, link)
+        }
+    }
+}
+
+/// If `target` is an absolute path inside `root`, return it rewritten relative to the directory
+/// containing `link` (which must also be inside `root`). Otherwise return `None`.
+pub fn relative_target(root: &Path, link: &Path, target: &Path) -> Option<PathBuf> {
+    if !target.is_absolute() {
+        return None;
+    }
+    let target = target.strip_prefix(root).ok()?;
+    let link_dir = link.parent()?.strip_prefix(root).ok()?;
+
+    let target: Vec<Component> = target.components().collect();
+    let link_dir: Vec<Component> = link_dir.components().collect();
+    let common = target
+        .iter()
+        .zip(link_dir.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut relative = PathBuf::new();
+    for _ in common..link_dir.len() {
+        relative.push("..");
+    }
+    for component in &target[common..] {
+        relative.push(component);
+    }
+    if relative.as_os_str().is_empty() {
+        relative.push(".");
+    }
+    Some(relative)
+}
+
+#[cfg(test)]
+mod test {
+    use super::relative_target;
+    use std::path::{Path, PathBuf};
+
+    const ROOT: &str = "/home/test/repo";
+
+    fn relative(link: &str, target: &str) -> Option<PathBuf> {
+        relative_target(Path::new(ROOT), Path::new(link), Path::new(target))
+    }
+
+    #[test]
+    fn sibling_file() {
+        assert_eq!(
+            relative("/home/test/repo/a/link", "/home/test/repo/a/file"),
+            Some(PathBuf::from("file"))
+        );
+    }
+
+    #[test]
+    fn across_directories() {
+        assert_eq!(
+            relative("/home/test/repo/a/b/link", "/home/test/repo/c/file"),
+            Some(PathBuf::from("../../c/file"))
+        );
+    }
+
+    #[test]
+    fn parent_directory() {
+        assert_eq!(
+            relative("/home/test/repo/a/b/link", "/home/test/repo/a"),
+            Some(PathBuf::from(".."))
+        );
+    }
+
+    #[test]
+    fn outside_root_is_kept() {
+        assert_eq!(relative("/home/test/repo/a/link", "/etc/passwd"), None);
+        assert_eq!(relative("/home/test/repo/a/link", "file"), None);
+    }
+}
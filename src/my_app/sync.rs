@@ -0,0 +1,394 @@
+use super::app_options::SyncOptions;
+use super::checksum;
+use super::ignore_file::{DirRules, IgnoreFile};
+use super::manifest::MANIFEST_FILE_NAME;
+use super::temp_file;
+use anyhow::{bail, Context, Result};
+use filetime::FileTime;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{BufReader, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Name of the file recording, in each synced directory, the state of its files after the last
+/// sync.
+pub const SYNC_STATE_FILE_NAME: &str = ".slimcopy_sync";
+
+/// Files of slimcopy itself at the root of a directory, which are never synced.
+const OWN_FILES: [&str; 2] = [SYNC_STATE_FILE_NAME, MANIFEST_FILE_NAME];
+
+/// Size and modification time of a file on one side.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct FileState {
+    size: u64,
+    mtime: SystemTime,
+}
+
+impl FileState {
+    fn of(meta: &fs::Metadata) -> Result<Self> {
+        Ok(FileState {
+            size: meta.len(),
+            mtime: meta.modified()?,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct StateFile {
+    /// Directory this one was synced with
+    peer: PathBuf,
+    /// States by path relative to both directories
+    files: BTreeMap<PathBuf, FileState>,
+}
+
+/// What to do with a file found on either side. Sides are 0 for A and 1 for B.
+#[derive(Debug, PartialEq, Eq)]
+enum Step {
+    /// Both sides agree, or neither has changed
+    Keep,
+    /// Copy the file from this side to the other
+    Copy(usize),
+    /// The file was deleted on this side, so delete it on the other
+    Delete(usize),
+    /// Left alone and reported, for the given reason
+    Conflict(&'static str),
+}
+
+/// Decide what to do with a file from its current state on both sides and its state after the
+/// last sync. `same_content` is only called when both sides have changed.
+fn decide<F>(
+    current: [Option<FileState>; 2],
+    last: [Option<FileState>; 2],
+    same_content: F,
+) -> Result<Step>
+where
+    F: FnOnce() -> Result<bool>,
+{
+    let changed = [current[0] != last[0], current[1] != last[1]];
+    Ok(match (changed, current) {
+        ([false, false], _) | ([true, true], [None, None]) => Step::Keep,
+        ([true, false], [Some(_), _]) => Step::Copy(0),
+        ([true, false], [None, _]) => Step::Delete(0),
+        ([false, true], [_, Some(_)]) => Step::Copy(1),
+        ([false, true], [_, None]) => Step::Delete(1),
+        (_, [Some(_), Some(_)]) => {
+            if same_content()? {
+                Step::Keep
+            } else if last[0].is_some() || last[1].is_some() {
+                Step::Conflict("changed on both sides")
+            } else {
+                // Never synced before, so neither side can be told to be the right one
+                Step::Conflict("different on both sides before the first sync")
+            }
+        }
+        _ => Step::Conflict("changed on one side and deleted on the other"),
+    })
+}
+
+/// Bring two directories up to date with each other, as `slimcopy sync` does. Files changed on
+/// one side since the last sync are copied to the other side, and files deleted on one side are
+/// deleted on the other. Files changed on both sides are reported and left alone.
+pub fn sync(options: &SyncOptions) -> Result<()> {
+    let roots = [options.a.clone(), options.b.clone()];
+    let ignore_file = IgnoreFile::load(&roots[0], &options.rules)?;
+    let last = [
+        load_state(&roots[0], &roots[1])?,
+        load_state(&roots[1], &roots[0])?,
+    ];
+
+    let mut files = BTreeMap::new();
+    let mut conflicts = Vec::new();
+    collect_files(
+        &ignore_file,
+        &DirRules::empty(),
+        &roots,
+        Path::new(""),
+        &mut files,
+        &mut conflicts,
+    )?;
+    // Files deleted on both sides are only known from the state
+    for path in last[0].keys().chain(last[1].keys()) {
+        files.entry(path.clone()).or_insert([None, None]);
+    }
+
+    let mut copied = [0, 0];
+    let mut deleted = 0;
+    let mut state = [BTreeMap::new(), BTreeMap::new()];
+    for (path, current) in files.iter() {
+        let previous = [last[0].get(path).copied(), last[1].get(path).copied()];
+        let paths = [roots[0].join(path), roots[1].join(path)];
+        let step = decide(*current, previous, || {
+            Ok(checksum::file_hash(&paths[0])? == checksum::file_hash(&paths[1])?)
+        })?;
+
+        let synced = match step {
+            Step::Keep => *current,
+            Step::Copy(from) => {
+                let to = 1 - from;
+                println!("Copy {} -> {}", paths[from].display(), paths[to].display());
+                copied[to] += 1;
+                if options.dry_run {
+                    continue;
+                }
+                copy_file(&paths[from], &paths[to])?;
+                [
+                    Some(FileState::of(&paths[0].metadata()?)?),
+                    Some(FileState::of(&paths[1].metadata()?)?),
+                ]
+            }
+            Step::Delete(from) => {
+                let to = 1 - from;
+                println!("Delete {}", paths[to].display());
+                deleted += 1;
+                if !options.dry_run {
+                    delete_file(&roots[to], &paths[to], &roots[from])?;
+                }
+                [None, None]
+            }
+            Step::Conflict(reason) => {
+                conflicts.push((path.clone(), reason));
+                // Keep the last state, so that the conflict is reported until it is resolved
+                previous
+            }
+        };
+        for side in 0..2 {
+            if let (Some(_), Some(file_state)) = (synced[1 - side], synced[side]) {
+                state[side].insert(path.clone(), file_state);
+            }
+        }
+    }
+
+    if !options.dry_run {
+        save_state(&roots[0], &roots[1], &state[0])?;
+        save_state(&roots[1], &roots[0], &state[1])?;
+    }
+
+    for (path, reason) in conflicts.iter() {
+        println!("Conflict {}: {}", path.display(), reason);
+    }
+    println!(
+        "\n{:>6} file(s) copied to {}",
+        copied[0],
+        roots[0].display()
+    );
+    println!("{:>6} file(s) copied to {}", copied[1], roots[1].display());
+    println!("{:>6} file(s) deleted", deleted);
+    println!("{:>6} conflict(s)", conflicts.len());
+    if !conflicts.is_empty() {
+        bail!("{} conflict(s) left to resolve", conflicts.len());
+    }
+    Ok(())
+}
+
+/// Collect the files of the directory `dir`, relative to both roots, that the rules do not
+/// ignore. Rules are evaluated on the paths of the first root, whose rule files are the ones
+/// read. Paths that are a file on one side and a directory on the other are added to
+/// `conflicts`. Symbolic links are not synced.
+fn collect_files(
+    ignore_file: &IgnoreFile,
+    parent_rules: &DirRules,
+    roots: &[PathBuf; 2],
+    dir: &Path,
+    files: &mut BTreeMap<PathBuf, [Option<FileState>; 2]>,
+    conflicts: &mut Vec<(PathBuf, &'static str)>,
+) -> Result<()> {
+    let rules = ignore_file.dir_rules(&roots[0].join(dir), parent_rules)?;
+    let mut names = BTreeSet::new();
+    for root in roots.iter() {
+        let path = root.join(dir);
+        if path.is_dir() {
+            for entry in path.read_dir()? {
+                names.insert(entry?.file_name());
+            }
+        }
+    }
+
+    for name in names {
+        if (dir.as_os_str().is_empty() && OWN_FILES.iter().any(|own| name == *own))
+            || temp_file::is_temp_file(&name)
+        {
+            continue;
+        }
+        let path = dir.join(&name);
+        let metas = [
+            roots[0].join(&path).symlink_metadata().ok(),
+            roots[1].join(&path).symlink_metadata().ok(),
+        ];
+        let metas: Vec<&fs::Metadata> = metas.iter().flatten().collect();
+        if metas.iter().any(|meta| meta.file_type().is_symlink()) {
+            continue;
+        }
+        let is_dir = metas[0].is_dir();
+        if metas.iter().any(|meta| meta.is_dir() != is_dir) {
+            conflicts.push((path, "a file on one side and a directory on the other"));
+            continue;
+        }
+        if ignore_file.is_ignored_in(&rules, roots[0].join(&path), is_dir) {
+            continue;
+        }
+
+        if is_dir {
+            collect_files(ignore_file, &rules, roots, &path, files, conflicts)?;
+        } else {
+            let mut current = [None, None];
+            for (side, root) in roots.iter().enumerate() {
+                if let Ok(meta) = root.join(&path).metadata() {
+                    current[side] = Some(FileState::of(&meta)?);
+                }
+            }
+            files.insert(path, current);
+        }
+    }
+    Ok(())
+}
+
+/// Copy a file through a temporary file, keeping its modification time so that both sides
+/// record the same state.
+fn copy_file(from: &Path, to: &Path) -> Result<()> {
+    let dir = to.parent().unwrap();
+    fs::create_dir_all(dir)
+        .with_context(|| format!("Cannot create directory \"{}\"", dir.display()))?;
+    temp_file::replace_with(to, |temp_path| {
+        fs::copy(from, temp_path)?;
+        let mtime = FileTime::from_last_modification_time(&from.metadata()?);
+        Ok(filetime::set_file_mtime(temp_path, mtime)?)
+    })
+    .with_context(|| format!("Failed to copy file to \"{}\"", to.display()))
+}
+
+/// Delete a file of the directory `root`, and the directories containing it that became empty
+/// and no longer exist in `other_root`.
+fn delete_file(root: &Path, path: &Path, other_root: &Path) -> Result<()> {
+    fs::remove_file(path).with_context(|| format!("Cannot delete file \"{}\"", path.display()))?;
+    let mut dir = path.parent();
+    while let Some(current) = dir.filter(|dir| *dir != root) {
+        let relative = current.strip_prefix(root)?;
+        if other_root.join(relative).exists() || fs::remove_dir(current).is_err() {
+            break;
+        }
+        dir = current.parent();
+    }
+    Ok(())
+}
+
+/// The states recorded in `dir` by its last sync with `peer`.
+fn load_state(dir: &Path, peer: &Path) -> Result<BTreeMap<PathBuf, FileState>> {
+    let path = dir.join(SYNC_STATE_FILE_NAME);
+    match File::open(&path) {
+        Ok(file) => {
+            let state: StateFile =
+                serde_json::from_reader(BufReader::new(file)).with_context(|| {
+                    format!(
+                        "Invalid sync state \"{}\", delete it to start over",
+                        path.display()
+                    )
+                })?;
+            // A state recorded with another directory says nothing about this one
+            Ok(if state.peer == peer {
+                state.files
+            } else {
+                BTreeMap::new()
+            })
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e).with_context(|| format!("Cannot read \"{}\"", path.display())),
+    }
+}
+
+fn save_state(dir: &Path, peer: &Path, files: &BTreeMap<PathBuf, FileState>) -> Result<()> {
+    let path = dir.join(SYNC_STATE_FILE_NAME);
+    temp_file::write_atomically(&path, |writer| {
+        Ok(serde_json::to_writer(
+            writer,
+            &StateFile {
+                peer: peer.to_path_buf(),
+                files: files.clone(),
+            },
+        )?)
+    })
+    .with_context(|| format!("Cannot write sync state \"{}\"", path.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decide, FileState, Step};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn state(size: u64, secs: u64) -> Option<FileState> {
+        Some(FileState {
+            size,
+            mtime: UNIX_EPOCH + Duration::from_secs(secs),
+        })
+    }
+
+    #[test]
+    fn changes_are_copied_from_the_side_that_changed() {
+        let old = state(1, 10);
+        let new = state(2, 20);
+        let differ = || Ok(false);
+        assert_eq!(decide([old, old], [old, old], differ).unwrap(), Step::Keep);
+        assert_eq!(
+            decide([new, old], [old, old], differ).unwrap(),
+            Step::Copy(0)
+        );
+        assert_eq!(
+            decide([old, new], [old, old], differ).unwrap(),
+            Step::Copy(1)
+        );
+        assert_eq!(
+            decide([None, old], [old, old], differ).unwrap(),
+            Step::Delete(0)
+        );
+        assert_eq!(
+            decide([old, None], [old, old], differ).unwrap(),
+            Step::Delete(1)
+        );
+        // A file restored to an older version is still a change
+        assert_eq!(
+            decide([old, new], [new, new], differ).unwrap(),
+            Step::Copy(0)
+        );
+    }
+
+    #[test]
+    fn changes_on_both_sides_are_conflicts() {
+        let old = state(1, 10);
+        let (a, b) = (state(2, 20), state(3, 30));
+        assert!(matches!(
+            decide([a, b], [old, old], || Ok(false)).unwrap(),
+            Step::Conflict(_)
+        ));
+        assert_eq!(decide([a, b], [old, old], || Ok(true)).unwrap(), Step::Keep);
+        assert!(matches!(
+            decide([a, None], [old, old], || Ok(false)).unwrap(),
+            Step::Conflict(_)
+        ));
+        assert_eq!(
+            decide([None, None], [old, old], || Ok(false)).unwrap(),
+            Step::Keep
+        );
+    }
+
+    #[test]
+    fn different_files_are_conflicts_on_the_first_sync() {
+        let (a, b) = (state(2, 20), state(3, 30));
+        assert!(matches!(
+            decide([a, b], [None, None], || Ok(false)).unwrap(),
+            Step::Conflict(_)
+        ));
+        assert!(matches!(
+            decide([b, a], [None, None], || Ok(false)).unwrap(),
+            Step::Conflict(_)
+        ));
+        assert_eq!(
+            decide([a, b], [None, None], || Ok(true)).unwrap(),
+            Step::Keep
+        );
+        assert_eq!(
+            decide([a, None], [None, None], || Ok(false)).unwrap(),
+            Step::Copy(0)
+        );
+    }
+}
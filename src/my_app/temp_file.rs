@@ -0,0 +1,158 @@
+use anyhow::Context;
+use std::ffi::OsStr;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Start of the names of files being written, which are renamed into place once complete.
+pub const TEMP_FILE_PREFIX: &str = ".slimcopy_tmp.";
+
+static NEXT: AtomicU64 = AtomicU64::new(0);
+
+/// A new path to write `dest_path` to before renaming it into place, so that an interrupted
+/// copy never leaves a truncated file in place of the real one. It is in the same directory, so
+/// that the rename stays on one file system, and holds the process id, so that concurrent runs
+/// never write to the same file.
+pub fn temp_path(dest_path: &Path) -> PathBuf {
+    dest_path.with_file_name(format!(
+        "{}{}.{}",
+        TEMP_FILE_PREFIX,
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// Write `path` through a file given by [`temp_path`], so that it is never left half written:
+/// `write` fills the temporary file, which then takes the place of `path`, or is deleted if
+/// anything fails.
+pub fn replace_with<T, F>(path: &Path, write: F) -> anyhow::Result<T>
+where
+    F: FnOnce(&Path) -> anyhow::Result<T>,
+{
+    let temp_path = temp_path(path);
+    let result = write(&temp_path).and_then(|value| {
+        fs::rename(&temp_path, path)
+            .with_context(|| format!("Failed to move file to \"{}\"", path.display()))?;
+        Ok(value)
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// Replace `path` with the content given by `write`, through [`replace_with`] so that readers
+/// never see a partly written file.
+pub fn write_atomically<F>(path: &Path, write: F) -> anyhow::Result<()>
+where
+    F: FnOnce(&mut BufWriter<File>) -> anyhow::Result<()>,
+{
+    replace_with(path, |temp_path| {
+        let mut writer = BufWriter::new(File::create(temp_path)?);
+        write(&mut writer)?;
+        writer.flush()?;
+        writer
+            .into_inner()
+            .map_err(io::IntoInnerError::into_error)?
+            .sync_all()?;
+        Ok(())
+    })
+}
+
+/// Whether a file name is one given by [`temp_path`].
+pub fn is_temp_file(name: &OsStr) -> bool {
+    name.to_str()
+        .is_some_and(|name| name.starts_with(TEMP_FILE_PREFIX))
+}
+
+/// Delete the files that interrupted runs left in `dir`, returning their paths.
+pub fn remove_stale(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut removed = Vec::new();
+    for entry in dir.read_dir()? {
+        let entry = entry?;
+        if entry.file_name().to_str().is_some_and(is_stale) && entry.file_type()?.is_file() {
+            fs::remove_file(entry.path())?;
+            removed.push(entry.path());
+        }
+    }
+    Ok(removed)
+}
+
+/// Whether a file name given by [`temp_path`] belongs to a process that is gone.
+fn is_stale(name: &str) -> bool {
+    let pid = name
+        .strip_prefix(TEMP_FILE_PREFIX)
+        .and_then(|rest| rest.split('.').next())
+        .and_then(|pid| pid.parse::<u32>().ok());
+    match pid {
+        Some(pid) => pid != std::process::id() && !is_running(pid),
+        _ => false,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn is_running(pid: u32) -> bool {
+    Path::new("/proc").join(pid.to_string()).exists()
+}
+
+/// Without a cheap way to tell, only the files of the current process are kept.
+#[cfg(not(target_os = "linux"))]
+fn is_running(_pid: u32) -> bool {
+    false
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_temp_file, remove_stale, temp_path, write_atomically, TEMP_FILE_PREFIX};
+    use std::fs;
+    use std::io::Write;
+    use std::path::Path;
+
+    #[test]
+    fn temp_files_are_unique_and_next_to_their_file() {
+        let dest_path = Path::new("/dest/sub/a.txt");
+        let first = temp_path(dest_path);
+        let second = temp_path(dest_path);
+
+        assert_ne!(first, second);
+        assert_eq!(first.parent(), dest_path.parent());
+        assert!(is_temp_file(first.file_name().unwrap()));
+        assert!(!is_temp_file(dest_path.file_name().unwrap()));
+    }
+
+    #[test]
+    fn failed_writes_leave_the_file_alone() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("state");
+        fs::write(&path, "old").unwrap();
+
+        let result = write_atomically(&path, |writer| {
+            writer.write_all(b"partial")?;
+            anyhow::bail!("interrupted")
+        });
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "old");
+        assert_eq!(fs::read_dir(temp.path()).unwrap().count(), 1);
+
+        write_atomically(&path, |writer| Ok(writer.write_all(b"new")?)).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(fs::read_dir(temp.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn only_files_of_gone_processes_are_stale() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let stale = dir.join(format!("{}{}.0", TEMP_FILE_PREFIX, u32::MAX));
+        let own = temp_path(&dir.join("a.txt"));
+        fs::write(&stale, "").unwrap();
+        fs::write(&own, "").unwrap();
+        fs::write(dir.join("a.txt"), "").unwrap();
+
+        assert_eq!(remove_stale(dir).unwrap(), vec![stale.clone()]);
+        assert!(!stale.exists());
+        assert!(own.exists());
+        assert!(dir.join("a.txt").exists());
+    }
+}
@@ -1,32 +1,86 @@
+use super::report::Action;
+use serde::Serialize;
 use size_format::SizeFormatterBinary;
 use std::fmt::Display;
 use std::iter::Sum;
 use std::ops::Add;
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct TypeCounter {
     copied: u64,
+    linked: u64,
     skipped: u64,
     symlink: u64,
     no_update: u64,
+    deleted: u64,
+    identical: u64,
+    conflict: u64,
+    failed: u64,
+    mismatched: u64,
     copied_size: u64,
+    linked_size: u64,
     skipped_size: u64,
     no_update_size: u64,
+    deleted_size: u64,
+    identical_size: u64,
+    conflict_size: u64,
 }
 
 impl TypeCounter {
     pub fn new() -> Self {
         TypeCounter {
             copied: 0,
+            linked: 0,
             skipped: 0,
             symlink: 0,
             no_update: 0,
+            deleted: 0,
+            identical: 0,
+            conflict: 0,
+            failed: 0,
+            mismatched: 0,
             copied_size: 0,
+            linked_size: 0,
             skipped_size: 0,
             no_update_size: 0,
+            deleted_size: 0,
+            identical_size: 0,
+            conflict_size: 0,
         }
     }
 
+    /// Count a single file according to what was done with it.
+    pub fn count(self, action: Action, size: u64) -> Self {
+        match action {
+            Action::Copied => self.count_copied(size),
+            Action::Linked => self.count_linked(size),
+            Action::Skipped => self.count_skipped(1, size),
+            Action::Old => self.count_no_update(size),
+            Action::Identical => self.count_identical(size),
+            Action::Conflict => self.count_conflict(size),
+            Action::Symlink => self.count_symlink(),
+            Action::Deleted => self.count_deleted(size),
+            Action::Mismatch => self.count_mismatched(),
+            Action::Error => self.count_failed(),
+        }
+    }
+
+    /// Number of files and their total size for each action.
+    pub fn by_action(&self) -> Vec<(Action, u64, u64)> {
+        vec![
+            (Action::Copied, self.copied, self.copied_size),
+            (Action::Linked, self.linked, self.linked_size),
+            (Action::Old, self.no_update, self.no_update_size),
+            (Action::Identical, self.identical, self.identical_size),
+            (Action::Conflict, self.conflict, self.conflict_size),
+            (Action::Skipped, self.skipped, self.skipped_size),
+            (Action::Deleted, self.deleted, self.deleted_size),
+            (Action::Symlink, self.symlink, 0),
+            (Action::Mismatch, self.mismatched, 0),
+            (Action::Error, self.failed, 0),
+        ]
+    }
+
     pub fn count_copied(self, size: u64) -> Self {
         Self {
             copied: self.copied + 1,
@@ -35,6 +89,14 @@ impl TypeCounter {
         }
     }
 
+    pub fn count_linked(self, size: u64) -> Self {
+        Self {
+            linked: self.linked + 1,
+            linked_size: self.linked_size + size,
+            ..self
+        }
+    }
+
     pub fn count_skipped(self, count: u64, size: u64) -> Self {
         Self {
             skipped: self.skipped + count,
@@ -57,6 +119,44 @@ impl TypeCounter {
             ..self
         }
     }
+
+    pub fn count_failed(self) -> Self {
+        Self {
+            failed: self.failed + 1,
+            ..self
+        }
+    }
+
+    pub fn count_mismatched(self) -> Self {
+        Self {
+            mismatched: self.mismatched + 1,
+            ..self
+        }
+    }
+
+    pub fn count_deleted(self, size: u64) -> Self {
+        Self {
+            deleted: self.deleted + 1,
+            deleted_size: self.deleted_size + size,
+            ..self
+        }
+    }
+
+    pub fn count_identical(self, size: u64) -> Self {
+        Self {
+            identical: self.identical + 1,
+            identical_size: self.identical_size + size,
+            ..self
+        }
+    }
+
+    pub fn count_conflict(self, size: u64) -> Self {
+        Self {
+            conflict: self.conflict + 1,
+            conflict_size: self.conflict_size + size,
+            ..self
+        }
+    }
 }
 
 impl<'a> Add<&'a TypeCounter> for TypeCounter {
@@ -65,12 +165,22 @@ impl<'a> Add<&'a TypeCounter> for TypeCounter {
     fn add(self, other: &'a Self) -> Self {
         Self {
             copied: self.copied + other.copied,
+            linked: self.linked + other.linked,
             skipped: self.skipped + other.skipped,
             symlink: self.symlink + other.symlink,
             no_update: self.no_update + other.no_update,
+            deleted: self.deleted + other.deleted,
+            identical: self.identical + other.identical,
+            conflict: self.conflict + other.conflict,
+            failed: self.failed + other.failed,
+            mismatched: self.mismatched + other.mismatched,
             copied_size: self.copied_size + other.copied_size,
+            linked_size: self.linked_size + other.linked_size,
             skipped_size: self.skipped_size + other.skipped_size,
             no_update_size: self.no_update_size + other.no_update_size,
+            deleted_size: self.deleted_size + other.deleted_size,
+            identical_size: self.identical_size + other.identical_size,
+            conflict_size: self.conflict_size + other.conflict_size,
         }
     }
 }
@@ -86,16 +196,32 @@ impl Display for TypeCounter {
         write!(
             f,
             "{:>6} file(s) copied,      size = {:>8}B
+{:>6} file(s) hard-linked, size = {:>8}B
 {:>6} file(s) not updated, size = {:>8}B
+{:>6} file(s) identical,   size = {:>8}B
+{:>6} file(s) in conflict, size = {:>8}B
 {:>6} file(s) skipped,     size = {:>8}B
-{:>6} symbolic link(s)",
+{:>6} file(s) deleted,     size = {:>8}B
+{:>6} symbolic link(s)
+{:>6} file(s) failed
+{:>6} file(s) mismatched after copy",
             self.copied,
             SizeFormatterBinary::new(self.copied_size),
+            self.linked,
+            SizeFormatterBinary::new(self.linked_size),
             self.no_update,
             SizeFormatterBinary::new(self.no_update_size),
+            self.identical,
+            SizeFormatterBinary::new(self.identical_size),
+            self.conflict,
+            SizeFormatterBinary::new(self.conflict_size),
             self.skipped,
             SizeFormatterBinary::new(self.skipped_size),
-            self.symlink
+            self.deleted,
+            SizeFormatterBinary::new(self.deleted_size),
+            self.symlink,
+            self.failed,
+            self.mismatched
         )
     }
 }
@@ -0,0 +1,108 @@
+use super::app_options::VerifyOptions;
+use super::checksum;
+use super::ignore_file::{DirRules, IgnoreFile};
+use anyhow::{bail, Result};
+use rayon::prelude::*;
+use std::path::Path;
+
+/// Result of checking a copy against its source file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Match,
+    Differs,
+    Missing,
+}
+
+/// Compare the content of a copy with its source.
+pub fn compare_file(src_path: &Path, dest_path: &Path) -> Result<Outcome> {
+    let dest_meta = match dest_path.metadata() {
+        Ok(meta) if meta.is_file() => meta,
+        _ => return Ok(Outcome::Missing),
+    };
+    if src_path.metadata()?.len() != dest_meta.len()
+        || checksum::file_hash(src_path)? != checksum::file_hash(dest_path)?
+    {
+        return Ok(Outcome::Differs);
+    }
+    Ok(Outcome::Match)
+}
+
+/// Check that every file of the source that the rules do not ignore has an identical copy in
+/// the destination, as `slimcopy verify` does. Symbolic links are not checked.
+pub fn verify(options: &VerifyOptions) -> Result<()> {
+    let ignore_file = IgnoreFile::load(&options.src, &options.rules)?;
+    let (checked, mismatched) =
+        verify_dir(&ignore_file, &DirRules::empty(), &options.src, options)?;
+
+    println!("\n{:>6} file(s) checked", checked);
+    println!("{:>6} file(s) mismatched", mismatched);
+    if mismatched > 0 {
+        bail!("{} file(s) do not match the source", mismatched);
+    }
+    Ok(())
+}
+
+/// Returns the number of files checked and of mismatches found.
+fn verify_dir(
+    ignore_file: &IgnoreFile,
+    parent_rules: &DirRules,
+    dir: &Path,
+    options: &VerifyOptions,
+) -> Result<(u64, u64)> {
+    let rules = ignore_file.dir_rules(dir, parent_rules)?;
+    dir.read_dir()?
+        .collect::<std::io::Result<Vec<_>>>()?
+        .par_iter()
+        .map(|entry| {
+            let path = entry.path();
+            let file_type = entry.file_type()?;
+            if file_type.is_symlink()
+                || ignore_file.is_ignored_in(&rules, &path, file_type.is_dir())
+            {
+                return Ok((0, 0));
+            }
+            if file_type.is_dir() {
+                return verify_dir(ignore_file, &rules, &path, options);
+            }
+
+            let dest_path = options.dest.join(path.strip_prefix(&options.src)?);
+            match compare_file(&path, &dest_path)? {
+                Outcome::Match => Ok((1, 0)),
+                Outcome::Differs => {
+                    println!("Differs {}", dest_path.display());
+                    Ok((1, 1))
+                }
+                Outcome::Missing => {
+                    println!("Missing {}", dest_path.display());
+                    Ok((1, 1))
+                }
+            }
+        })
+        .collect::<Result<Vec<_>>>()
+        .map(|v| {
+            v.iter().fold((0, 0), |(checked, mismatched), (c, m)| {
+                (checked + c, mismatched + m)
+            })
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{compare_file, Outcome};
+    use std::fs;
+
+    #[test]
+    fn copies_are_compared_by_content() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let src = dir.join("src.txt");
+        let dest = dir.join("dest.txt");
+        fs::write(&src, "content").unwrap();
+
+        assert_eq!(compare_file(&src, &dest).unwrap(), Outcome::Missing);
+        fs::write(&dest, "content").unwrap();
+        assert_eq!(compare_file(&src, &dest).unwrap(), Outcome::Match);
+        fs::write(&dest, "CONTENT").unwrap();
+        assert_eq!(compare_file(&src, &dest).unwrap(), Outcome::Differs);
+    }
+}
@@ -0,0 +1,318 @@
+use super::ignore_file::{DirRules, IgnoreFile};
+use super::type_counter::TypeCounter;
+use super::working_indicator::WorkingIndicator;
+use super::MyApp;
+use anyhow::{bail, Result};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::BTreeSet;
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+/// How long the source must stay quiet before the changes are copied, so that a burst of
+/// changes, e.g. from a build, is handled at once.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+type Events = notify::Result<Event>;
+
+impl MyApp {
+    /// Copy the source as `run` does, then keep copying the paths that change, and deleting
+    /// the ones removed in mirror mode, until interrupted. The whole source is copied again
+    /// when the rules change.
+    pub fn watch(mut self) -> Result<()> {
+        let (sender, events) = mpsc::channel();
+        // Watch first, so that nothing changed during the first run is missed
+        let mut watcher = self.start_watching(&sender)?;
+        self.run_once()?;
+        // Progress is meaningless for a few paths at a time
+        *self.progress.lock().unwrap() = WorkingIndicator::new(0, false);
+
+        loop {
+            println!(
+                "\nWatching \"{}\" for changes...",
+                self.options.src.display()
+            );
+            let changed = next_batch(&events)?;
+            if changed.iter().any(|path| self.is_rule_file(path)) {
+                match self.reload_rules() {
+                    Ok(()) => {
+                        watcher = self.start_watching(&sender)?;
+                        self.run_once()?;
+                        continue;
+                    }
+                    // The other changes of the batch, e.g. new directories to watch, still count
+                    Err(e) => eprintln!(
+                        "Cannot reload the rules, keeping the previous ones: {:#}",
+                        e
+                    ),
+                }
+            }
+            self.apply_changes(&changed, &mut watcher)?;
+        }
+    }
+
+    /// Load the rules again, including a rules file created in the source since the start.
+    fn reload_rules(&mut self) -> Result<()> {
+        self.ignore_file = IgnoreFile::load(&self.options.src, &self.options.rules)?;
+        self.log.add("Rules changed, copy everything again");
+        Ok(())
+    }
+
+    /// Run over the whole source, reporting failed files without stopping.
+    fn run_once(&self) -> Result<()> {
+        let count = self.run()?;
+        println!("\n{}", count);
+        if let Err(e) = self.check_failures() {
+            eprintln!("{:#}", e);
+        }
+        self.failures.lock().unwrap().clear();
+        Ok(())
+    }
+
+    fn start_watching(&self, sender: &Sender<Events>) -> Result<RecommendedWatcher> {
+        let sender = sender.clone();
+        let mut watcher = notify::recommended_watcher(move |event| {
+            let _ = sender.send(event);
+        })?;
+        self.watch_tree(&mut watcher, &self.options.src, &DirRules::empty())?;
+        Ok(watcher)
+    }
+
+    /// Watch `dir` and the directories below it that the rules do not ignore, so that changes
+    /// in ignored directories, such as build outputs, are never even reported. `parent_rules`
+    /// are the rules of the directories containing `dir`.
+    fn watch_tree(
+        &self,
+        watcher: &mut RecommendedWatcher,
+        dir: &Path,
+        parent_rules: &DirRules,
+    ) -> Result<()> {
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        let rules = self.ignore_file.dir_rules(dir, parent_rules)?;
+        for entry in dir.read_dir()? {
+            let entry = entry?;
+            let path = entry.path();
+            if entry.file_type()?.is_dir()
+                && !path.starts_with(&self.options.dest)
+                && !self.ignore_file.is_ignored_in(&rules, &path, true)
+            {
+                self.watch_tree(watcher, &path, &rules)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn is_rule_file(&self, path: &Path) -> bool {
+        self.ignore_file.is_rule_file(path)
+            || self.options.rules.include_file.as_deref() == Some(path)
+    }
+
+    /// Copy the changed paths, or delete their copies if they were removed and mirroring. A
+    /// failure is reported and the other paths are still handled.
+    fn apply_changes(
+        &self,
+        changed: &BTreeSet<PathBuf>,
+        watcher: &mut RecommendedWatcher,
+    ) -> Result<()> {
+        // Paths inside a directory that is copied as a whole are already handled
+        let mut copied_dir: Option<&Path> = None;
+        for path in changed.iter() {
+            if copied_dir.is_some_and(|dir| path.starts_with(dir))
+                || path.starts_with(&self.options.dest)
+                || !path.starts_with(&self.options.src)
+                || *path == self.options.src
+            {
+                continue;
+            }
+            if let Err(e) = self.apply_change(path, watcher) {
+                eprintln!("Error: {:#}", e);
+            }
+            if path.is_dir() {
+                copied_dir = Some(path);
+            }
+        }
+
+        self.verify_written();
+        // The manifest still holds the entries of the paths left alone
+        self.save_manifest(Ok(TypeCounter::new()))?;
+        if let Err(e) = self.check_failures() {
+            eprintln!("{:#}", e);
+        }
+        self.failures.lock().unwrap().clear();
+        Ok(())
+    }
+
+    fn apply_change(&self, path: &Path, watcher: &mut RecommendedWatcher) -> Result<()> {
+        let parent = path.parent().unwrap().strip_prefix(&self.options.src)?;
+        let dirs: Vec<Component> = parent.components().collect();
+        self.with_rules_below(&DirRules::empty(), &self.options.src, &dirs, |rules| {
+            let meta = path.symlink_metadata();
+            let is_dir = meta.as_ref().is_ok_and(|meta| meta.is_dir());
+            if self.ignore_file.is_ignored_in(rules, path, is_dir) {
+                return Ok(());
+            }
+            if meta.is_ok() {
+                if is_dir {
+                    self.watch_tree(watcher, path, rules)?;
+                }
+                self.traverse_tree(path, &[], rules)?;
+            } else if self.options.mirror {
+                let dest_path = self.dest_path_of(path)?;
+                if dest_path.symlink_metadata().is_ok() {
+                    self.prune_tree(&dest_path, true, rules)?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Call `f` with the rules of the directory `dir` joined with `rest`, read from `dir` down as
+    /// a traversal would. Nothing is done if one of the directories on the way is ignored.
+    fn with_rules_below<F>(
+        &self,
+        parent_rules: &DirRules,
+        dir: &Path,
+        rest: &[Component],
+        f: F,
+    ) -> Result<()>
+    where
+        F: FnOnce(&DirRules) -> Result<()>,
+    {
+        let rules = self.ignore_file.dir_rules(dir, parent_rules)?;
+        match rest.split_first() {
+            Some((next, rest)) => {
+                let path = dir.join(next);
+                if self.ignore_file.is_ignored_in(&rules, &path, true) {
+                    return Ok(());
+                }
+                self.with_rules_below(&rules, &path, rest, f)
+            }
+            None => f(&rules),
+        }
+    }
+}
+
+/// Wait for changes, then collect the paths changed until none has changed for `DEBOUNCE`.
+fn next_batch(events: &Receiver<Events>) -> Result<BTreeSet<PathBuf>> {
+    let mut changed = BTreeSet::new();
+    let mut event = events.recv()?;
+    loop {
+        match event {
+            Ok(event) if !matches!(event.kind, EventKind::Access(_)) => changed.extend(event.paths),
+            Ok(_) => (),
+            Err(e) => eprintln!("Cannot watch for changes: {}", e),
+        }
+        event = match events.recv_timeout(DEBOUNCE) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) if !changed.is_empty() => return Ok(changed),
+            Err(RecvTimeoutError::Timeout) => events.recv()?,
+            Err(RecvTimeoutError::Disconnected) => bail!("Stopped watching for changes"),
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::app_options::AppOptions;
+    use super::super::manifest::Manifest;
+    use super::{next_batch, MyApp};
+    use notify::event::{AccessKind, CreateKind, ModifyKind};
+    use notify::{Event, EventKind};
+    use std::collections::BTreeSet;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+    use std::sync::mpsc;
+
+    #[test]
+    fn bursts_of_changes_are_collected_at_once() {
+        let (sender, events) = mpsc::channel();
+        let event = |kind, path: &str| Ok(Event::new(kind).add_path(PathBuf::from(path)));
+        sender
+            .send(event(EventKind::Create(CreateKind::File), "/src/a"))
+            .unwrap();
+        sender
+            .send(event(EventKind::Access(AccessKind::Any), "/src/b"))
+            .unwrap();
+        sender
+            .send(event(EventKind::Modify(ModifyKind::Any), "/src/c"))
+            .unwrap();
+        sender
+            .send(event(EventKind::Modify(ModifyKind::Any), "/src/a"))
+            .unwrap();
+
+        let changed = next_batch(&events).unwrap();
+        assert_eq!(
+            changed.into_iter().collect::<Vec<_>>(),
+            vec![PathBuf::from("/src/a"), PathBuf::from("/src/c")]
+        );
+    }
+
+    #[test]
+    fn changes_update_the_manifest() {
+        let temp = tempfile::tempdir().unwrap();
+        let (src, dest) = (&temp.path().join("src"), &temp.path().join("dest"));
+        fs::create_dir_all(src).unwrap();
+        fs::write(src.join("a.txt"), "1").unwrap();
+        fs::write(src.join("b.txt"), "1").unwrap();
+        let options = || {
+            AppOptions::parse(&[
+                "--no-global-rules",
+                "--exclude",
+                "*.log",
+                "--manifest",
+                "--compare",
+                "size",
+                "--conflict",
+                "fail",
+                src.to_str().unwrap(),
+                dest.to_str().unwrap(),
+            ])
+            .unwrap()
+        };
+        // Start from the manifest of an earlier run
+        MyApp::new(options()).unwrap().run().unwrap();
+        let app = MyApp::new(options()).unwrap();
+        app.run().unwrap();
+        let mut watcher = notify::recommended_watcher(|_| {}).unwrap();
+        let changed = BTreeSet::from([src.join("a.txt")]);
+
+        // Copying the same file twice is no conflict, its copy was made by the first change
+        for content in ["22", "333"] {
+            fs::write(src.join("a.txt"), content).unwrap();
+            app.apply_changes(&changed, &mut watcher).unwrap();
+            app.check_failures().unwrap();
+            assert_eq!(fs::read_to_string(dest.join("a.txt")).unwrap(), content);
+        }
+        let manifest = Manifest::load(src, dest).unwrap();
+        assert_eq!(manifest.get(Path::new("a.txt")).unwrap().size, 3);
+        assert!(manifest.get(Path::new("b.txt")).is_some());
+    }
+
+    #[test]
+    fn a_rules_file_created_while_watching_is_applied() {
+        let temp = tempfile::tempdir().unwrap();
+        let (src, dest) = (&temp.path().join("src"), &temp.path().join("dest"));
+        fs::create_dir_all(src).unwrap();
+        fs::write(src.join("a.txt"), "1").unwrap();
+        fs::write(src.join("b.tmp"), "1").unwrap();
+        let mut app = MyApp::new(
+            AppOptions::parse(&[
+                "--no-global-rules",
+                "--exclude",
+                "*.log",
+                src.to_str().unwrap(),
+                dest.to_str().unwrap(),
+            ])
+            .unwrap(),
+        )
+        .unwrap();
+
+        fs::write(src.join(".slimcopy_rules"), "*.tmp\n").unwrap();
+        assert!(app.is_rule_file(&src.join(".slimcopy_rules")));
+        app.reload_rules().unwrap();
+        app.run().unwrap();
+
+        assert!(dest.join("a.txt").is_file());
+        assert!(!dest.join("b.tmp").exists());
+    }
+}
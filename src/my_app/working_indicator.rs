@@ -4,26 +4,34 @@ pub struct WorkingIndicator {
     max_step: u64,
     step: u64,
     last_percentage: u64,
+    visible: bool,
 }
 
 const BACKSPACE: &str = "\u{0008}";
 
 impl WorkingIndicator {
-    pub fn new(max: u64) -> Self {
+    pub fn new(max: u64, visible: bool) -> Self {
         WorkingIndicator {
             max_step: max,
             step: 0,
             last_percentage: 0,
+            visible,
         }
     }
 
     pub fn init(&mut self) {
+        if !self.visible {
+            return;
+        }
         print!("Processing....");
         self.draw();
     }
 
     pub fn update(&mut self, step: u64) {
         self.step += step;
+        if !self.visible || self.max_step == 0 {
+            return;
+        }
         let new_percentage = self.step * 100 / self.max_step;
         if new_percentage != self.last_percentage {
             self.last_percentage = new_percentage;
@@ -33,6 +41,9 @@ impl WorkingIndicator {
     }
 
     pub fn done(&self) {
+        if !self.visible {
+            return;
+        }
         println!("{}Done.", BACKSPACE.repeat(4));
     }
 